@@ -28,6 +28,10 @@ export class App extends LitElement {
         .playername[data-clocktype="world"] {
             font-variant: small-caps;
         }
+        .sheet {
+            font-size: 0.875rem;
+            margin: -0.75rem 0 0.5rem 0;
+        }
         #topbar {
             display: flex;
             flex-wrap: wrap;
@@ -71,6 +75,19 @@ export class App extends LitElement {
             display: block;
             margin-top: 0.5rem;
         }
+        #announcement {
+            position: fixed;
+            top: 40%;
+            left: 0;
+            width: 80%;
+            padding: 1rem 0;
+            text-align: center;
+            font-size: 2.5rem;
+            font-variant: small-caps;
+            color: var(--page-color);
+            background-color: var(--text-color);
+            pointer-events: none;
+        }
     `;
 
     static properties = {
@@ -78,18 +95,19 @@ export class App extends LitElement {
         _current_player_uuid: { type: String, state: true },
         _landmarks: { type: Object, state: true },
         _notes: { type: Object, state: true },
+        _rolls: { type: Object, state: true },
+        _announcement: { type: String, state: true },
         _socket: {},
+        _rev: {},
     }
 
     constructor() {
         super();
-        this._socket = new WebSocket('ws://localhost:3000/ws');
-
-        this._socket.addEventListener('open', (event) => {
-            this._request_full_sync();
-            const tab_num = localStorage.getItem("last_tab") || 1;
-            this._show_tab(tab_num);
-        });
+        // revision of the last update received, used to catch up after a dropped connection
+        this._rev = null;
+        // rolls can be announced before the full sync that brings the earlier ones arrives
+        this._rolls = {};
+        this._connect();
 
         this.addEventListener('full_sync', (event) => {
             this._request_full_sync()
@@ -131,10 +149,6 @@ export class App extends LitElement {
             this._current_player_uuid = event.detail;
         });
 
-        this._socket.addEventListener('message', (event) => {
-            this.handle_server_message(event);
-        });
-
         // until a user selects, whoever has most clocks will be on top (besides world)
         this._current_player_uuid = "";
 
@@ -156,10 +170,121 @@ export class App extends LitElement {
         window.delete_player = (id) => {
             this._socket.send(JSON.stringify({ "DeletePlayer": id }));
         }
+
+        window.undo = () => {
+            this._socket.send(JSON.stringify("Undo"));
+        }
+
+        window.redo = () => {
+            this._socket.send(JSON.stringify("Redo"));
+        }
+
+        // dice are rolled by the server so that everyone sees the same roll. kind is one of the
+        // rolls in the rules, e.g. { "type": "action", "position": "risky", "effect": "standard" },
+        // which the server resolves; without one the dice are just rolled
+        window.roll_dice = (pool, kind) => {
+            if (!kind || kind.type == "plain") {
+                this._socket.send(JSON.stringify({ "RollDice": pool }));
+            } else {
+                // resistance rolls cost the current player stress
+                const player_id = kind.type == "resistance" && this._current_player_uuid || null;
+                this._socket.send(JSON.stringify({ "Roll": { "pool": pool, "kind": kind, "player_id": player_id } }));
+            }
+        }
+
+        // GM only: roll from a new secret seed from now on, revealing the old one
+        window.rotate_seed = () => {
+            this._socket.send(JSON.stringify("RotateSeed"));
+        }
+
+        // take 2 stress to push yourself; running out of stress means choosing a trauma
+        window.push_yourself = (player_id) => {
+            this._socket.send(JSON.stringify({ "PushYourself": player_id }));
+        }
+
+        window.choose_trauma = (player_id, trauma) => {
+            this._socket.send(JSON.stringify({ "ChooseTrauma": [player_id, trauma] }));
+        }
+
+        // change one field of a player's character sheet, e.g. edit_sheet(id, "stress", 3) or
+        // edit_sheet(id, "action", { "action": "hunt", "rating": 2 })
+        window.edit_sheet = (player_id, field, value) => {
+            this._socket.send(JSON.stringify({ "EditSheet": [player_id, { "field": field, "value": value }] }));
+        }
+
+        window.list_trash = () => {
+            this._socket.send(JSON.stringify("ListTrash"));
+        }
+
+        window.restore = (id) => {
+            this._socket.send(JSON.stringify({ "RestoreTrash": id }));
+        }
+
+        window.purge_trash = (id) => {
+            this._socket.send(JSON.stringify({ "PurgeTrash": id }));
+        }
+
+        window.empty_trash = () => {
+            this._socket.send(JSON.stringify("EmptyTrash"));
+        }
+
+        // role is "gm", "spectator" or a player's uuid; expires_in is in seconds
+        window.create_invite = (role, label, expires_in = null) => {
+            if (role != "gm" && role != "spectator") {
+                role = { "role": "player", "player_id": role };
+            } else {
+                role = { "role": role };
+            }
+            this._socket.send(JSON.stringify({ "CreateInvite": { "role": role, "label": label, "expires_in": expires_in } }));
+        }
+
+        window.revoke_invite = (id) => {
+            this._socket.send(JSON.stringify({ "RevokeInvite": id }));
+        }
+
+        window.list_invites = () => {
+            this._socket.send(JSON.stringify("ListInvites"));
+        }
+    }
+
+    _connect() {
+        const protocol = window.location.protocol == "https:" ? "wss:" : "ws:";
+        const params = new URLSearchParams(window.location.search);
+        // e.g. /?campaign=oneshot plays the "oneshot" campaign instead of the default one
+        const campaign = params.get("campaign");
+        let path = campaign ? `/c/${encodeURIComponent(campaign)}/ws` : "/ws";
+        // the join token decides what the server lets us do
+        const token = params.get("token");
+        if (token) {
+            path += `?token=${encodeURIComponent(token)}`;
+        }
+        this._socket = new WebSocket(`${protocol}//${window.location.host}${path}`);
+
+        this._socket.addEventListener('open', (event) => {
+            if (this._rev == null) {
+                this._request_full_sync();
+                const tab_num = localStorage.getItem("last_tab") || 1;
+                this._show_tab(tab_num);
+            } else {
+                this._socket.send(JSON.stringify({ "Resume": { "since": this._rev } }));
+            }
+        });
+
+        this._socket.addEventListener('message', (event) => {
+            this.handle_server_message(event);
+        });
+
+        // reconnect (e.g. after a phone screen lock) and pick up where we left off
+        this._socket.addEventListener('close', (event) => {
+            setTimeout(() => this._connect(), 1000);
+        });
     }
 
     handle_server_message(event) {
         const update = JSON.parse(event.data);
+        if (update.rev != null) {
+            this._rev = update.rev;
+        }
 
         if (update.type == "Log") {
             console.log("INFO:", update);
@@ -168,10 +293,38 @@ export class App extends LitElement {
             this._players = update.players
             this._landmarks = update.landmarks
             this._notes = update.notes
+            this._rolls = update.rolls
         }
         else if (update.type == "Error") {
             console.error(update.text);
         }
+        else if (update.type == "Ack") {
+            if (update.result.status == "error") {
+                console.error("Request", update.request_id, "failed:", update.result.error);
+            }
+        }
+        else if (update.type == "Invite") {
+            const link = new URL(window.location.href);
+            link.searchParams.set("token", update.token);
+            console.log(`Invite for ${update.invite.label} created. It can only be shown once:`, link.toString());
+        }
+        else if (update.type == "Invites") {
+            console.table(update.invites);
+        }
+        else if (update.type == "Trash") {
+            console.table(update.items);
+        }
+        else if (update.type == "Seeded") {
+            console.log(`Dice were rolled from seed ${update.revealed}, and are now rolled from the seed with hash ${update.commitment}.`);
+        }
+        else if (update.type == "Purged") {
+            // nothing in the trash is shown
+        }
+        else if (update.type == "Roll") {
+            this._rolls[update.roll.id] = update.roll;
+            show_roll(update.roll);
+            this.requestUpdate();
+        }
         else if (update.type == "Clock") {
             this._players[update.player_id].clocks[update.clock_id] = update.clock;
             this.requestUpdate();
@@ -188,6 +341,50 @@ export class App extends LitElement {
             this._players[update.player_id]["name"] = update.player_name;
             this.requestUpdate();
         }
+        else if (update.type == "Sheet") {
+            let sheet = this._players[update.player_id]["sheet"];
+            if (update.field == "action") {
+                if (update.value.rating == 0) {
+                    delete sheet.actions[update.value.action];
+                } else {
+                    sheet.actions[update.value.action] = update.value.rating;
+                }
+            } else if (update.field == "xp") {
+                sheet.xp[update.value.track] = update.value.xp;
+            } else {
+                sheet[update.field] = update.value;
+            }
+            this.requestUpdate();
+        }
+        // the stress and trauma rules come one step at a time, so each can be announced
+        else if (update.type == "Stress") {
+            const player = this._players[update.player_id];
+            player.sheet.stress = update.stress;
+            const verb = update.change < 0 ? "clears" : "takes";
+            const because = { "push": " pushing", "resistance": " resisting", "gm": "" }[update.reason];
+            this._announce(`${player.name} ${verb} ${Math.abs(update.change)} stress${because}`);
+        }
+        else if (update.type == "Trauma") {
+            const player = this._players[update.player_id];
+            player.sheet.stress = 0;
+            player.sheet.trauma_pending = update.pending;
+            this._announce(`${player.name} suffers trauma`);
+            if (update.player_id == this._current_player_uuid) {
+                setTimeout(() => this._trauma_prompt(update.player_id, update.choices), 2000);
+            }
+        }
+        else if (update.type == "TraumaChosen") {
+            const player = this._players[update.player_id];
+            player.sheet.trauma = update.trauma;
+            player.sheet.trauma_pending = update.pending;
+            this._announce(`${player.name} becomes ${update.trauma[update.trauma.length - 1]}`);
+        }
+        else if (update.type == "Retired") {
+            const player = this._players[update.player_id];
+            player.sheet.retired = true;
+            player.sheet.trauma_pending = 0;
+            this._announce(`${player.name} retires`);
+        }
         else if (update.type == "DeletePlayer") {
             delete this._players[update.player_id];
             this.requestUpdate();
@@ -233,6 +430,32 @@ export class App extends LitElement {
         this._socket.send(JSON.stringify({ "RenamePlayer": [id, name] }));
     }
 
+    // show a message over the HUD for a few seconds, replacing any already shown
+    _announce(text) {
+        this._announcement = text;
+        clearTimeout(this._announcement_timeout);
+        this._announcement_timeout = setTimeout(() => this._announcement = null, 4000);
+    }
+
+    _trauma_prompt(id, choices) {
+        const trauma = prompt("Choose a trauma: " + choices.join(", ") + ".");
+        if (trauma == null) return;
+        choose_trauma(id, trauma.toString().trim().toLowerCase());
+    }
+
+    // the parts of a sheet that change most during a score, e.g. "cutter · stress 3/9 · cold"
+    _sheet_summary(sheet) {
+        if (sheet == null) return "";
+        let parts = [];
+        if (sheet.playbook) parts.push(sheet.playbook);
+        parts.push("stress " + sheet.stress + "/9");
+        parts = parts.concat(sheet.trauma);
+        if (sheet.retired) parts.push("retired");
+        const harm = sheet.harm.lesser.concat(sheet.harm.moderate, sheet.harm.severe ? [sheet.harm.severe] : []);
+        if (harm.length > 0) parts.push("harm: " + harm.join(", "));
+        return parts.join(" · ");
+    }
+
     _player_sort(a, b) { // sort based on who has the most clocks
         let sort_value = 0;
         if (a[1].name == "world") { // world clock always first
@@ -273,6 +496,7 @@ export class App extends LitElement {
                     oncontextmenu="navigator.clipboard.writeText('${id}'); return false;">
                     ${player.name}
                 </div>
+                <div class="sheet">${this._sheet_summary(player.sheet)}</div>
                 <bitd-clock-bar player_id="${id}" clocks="${JSON.stringify(player.clocks)}"></bitd-clock-bar>
             `;
         }
@@ -324,7 +548,8 @@ export class App extends LitElement {
                     <bitd-notes-list notes=${JSON.stringify(this._notes)}></bitd-notes-list>
                 </div>
             </div>
-            <bitd-sidebar players="${JSON.stringify(this._players)}"></bitd-sidebar>
+            ${this._announcement ? html`<div id="announcement">${this._announcement}</div>` : ""}
+            <bitd-sidebar players="${JSON.stringify(this._players)}" rolls="${JSON.stringify(this._rolls)}"></bitd-sidebar>
         `;
     }
 }
@@ -2,6 +2,7 @@ var numdice = 1;
 
 function new_die() {
     if (numdice < 6) {
+        match_pool();
         let dicerow = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelector("#sidebar .section #dice");
         dicerow.innerHTML += '<div class="die" onClick="roll_die(' + numdice + ')" style="background-color: var(--dice-default-color);">⋯</div>';
         numdice += 1;
@@ -10,6 +11,7 @@ function new_die() {
 
 function delete_die() {
     if (numdice >= 1) {
+        match_pool();
         let dice = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelectorAll("#sidebar .section #dice .die");
 
         let dicerow = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelector("#sidebar .section #dice");
@@ -28,9 +30,43 @@ function empty_dice() {
     dicerow.innerHTML = '';
 }
 
+// a roll shows as many dice as it rolled, which can differ from the pool, so lay out the pool
+// again before changing it
+function match_pool() {
+    let dicerow = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelector("#sidebar .section #dice");
+    if (dicerow.querySelectorAll(".die").length == numdice) {
+        return;
+    }
+    let color = dicerow.querySelector(".die")?.style.backgroundColor || "var(--dice-default-color)";
+    dicerow.innerHTML = '';
+    for (var i = 0; i < numdice; i++) {
+        dicerow.innerHTML += '<div class="die" onClick="roll_die(' + i + ')" style="background-color: ' + color + ';">⋯</div>';
+    }
+}
+
+// clicking any die rolls the whole pool
 function roll_die(num) {
-    let dice = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelectorAll("#sidebar .section #dice .die");
-    let oldnum = dice[num].innerHTML;
+    roll_all_dice();
+}
+
+// show a roll made by the server, which everyone at the table sees, leaving the pool as it was
+function show_roll(roll) {
+    let dicerow = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelector("#sidebar .section #dice");
+    let color = dicerow.querySelector(".die")?.style.backgroundColor || "var(--dice-default-color)";
+    dicerow.innerHTML = '';
+    for (var i = 0; i < roll.faces.length; i++) {
+        dicerow.innerHTML += '<div class="die" onClick="roll_die(' + i + ')" style="background-color: ' + color + ';">⋯</div>';
+    }
+
+    let dice = dicerow.querySelectorAll(".die");
+    for (var i = 0; i < roll.faces.length; i++) {
+        animate_die(dice[i], roll.faces[i]);
+    }
+}
+
+// flick through some other faces before landing on the rolled one
+function animate_die(die, face) {
+    let oldnum = die.innerHTML;
 
     var fake_choices = [1, 2, 3, 4, 5, 6];
     const index = fake_choices.indexOf(oldnum);
@@ -38,7 +74,7 @@ function roll_die(num) {
         fake_choices.splice(index, 1);
     }
 
-    // truly random fisher-yates shuffle
+    // the shuffle is only for show; the roll itself comes from the server
     for (let i = fake_choices.length - 1; i > 0; i--) {
         const j = Math.floor(Math.random() * (i + 1));
         const temp = fake_choices[i];
@@ -47,27 +83,33 @@ function roll_die(num) {
     }
 
     setTimeout(function(){
-        dice[num].innerHTML = fake_choices[0];
+        die.innerHTML = fake_choices[0];
     }, 75);
     setTimeout(function(){
-        dice[num].innerHTML = fake_choices[1];
+        die.innerHTML = fake_choices[1];
     }, 150);
     setTimeout(function(){
-        dice[num].innerHTML = fake_choices[2];
+        die.innerHTML = fake_choices[2];
     }, 225);
     setTimeout(function(){
-        dice[num].innerHTML = fake_choices[3];
+        die.innerHTML = fake_choices[3];
     }, 300);
 
     setTimeout(function(){
-        let newnum = Math.floor(Math.random()*6)+1;
-        dice[num].innerHTML = newnum;
+        die.innerHTML = face;
     }, 375);
 }
 
+// rolls for the rules may be made with no dice, which takes the lowest of two
 function roll_all_dice() {
-    for (var i = 0; i < numdice; i++) {
-        roll_die(i);
+    let sidebar = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot;
+    let kind = { "type": sidebar.querySelector("#rollkind").value };
+    if (kind.type == "action") {
+        kind.position = sidebar.querySelector("#position").value;
+        kind.effect = sidebar.querySelector("#effect").value;
+    }
+    if (numdice > 0 || kind.type != "plain") {
+        roll_dice(numdice, kind);
     }
 }
 
@@ -3,6 +3,9 @@ import { MAP_URL, MAP_DARK_URL } from './map.js'
 
 export const VERSION = "ver. 2023.08.18";
 
+// number of recent rolls listed under the dice
+const ROLLS_SHOWN = 5;
+
 export class Sidebar extends LitElement {
     // #lastupdate border code: https://stackoverflow.com/a/18064496
     static styles = css`
@@ -91,6 +94,16 @@ export class Sidebar extends LitElement {
             cursor: pointer;
         }
 
+        #rolls {
+            margin: 0.25rem 0;
+            padding: 0;
+            list-style: none;
+            font-size: 0.875rem;
+        }
+        #rolls li {
+            margin: 0.125rem 0;
+        }
+
         textarea {
             width: calc(100% - 1rem);
             font-size: 0.875rem;
@@ -169,14 +182,12 @@ export class Sidebar extends LitElement {
 
     static properties = {
         players: { type: Object },
-        _socket: {},
+        rolls: { type: Object },
     }
 
     constructor() {
         super();
 
-        this._socket = new WebSocket('ws://localhost:3000/ws');
-
         var storedTheme = localStorage.getItem('theme') ||
             (window.matchMedia("(prefers-color-scheme: dark)").matches ? "dark" : "light");
         if (storedTheme) {
@@ -201,7 +212,8 @@ export class Sidebar extends LitElement {
     }
 
     _request_full_sync() {
-        this._socket.send(JSON.stringify("FullSync"));
+        // full syncs are only sent back to the socket that asked, so ask through the app's socket
+        this.dispatchEvent(new CustomEvent("full_sync", { bubbles: true, composed: true }));
         console.log("Forced a full sync with the server.")
 
         const syncbutton = document.querySelector("bitd-app").shadowRoot.querySelector("bitd-sidebar").shadowRoot.querySelector("#tool3 span");
@@ -247,6 +259,36 @@ export class Sidebar extends LitElement {
         showbutton.style.display = "none";
     }
 
+    // newest first
+    _recent_rolls() {
+        return Object.values(this.rolls || {})
+            .sort((a, b) => b.time - a.time)
+            .slice(0, ROLLS_SHOWN);
+    }
+
+    _roller_name(roller) {
+        if (roller.role == "player") {
+            return (this.players || {})[roller.player_id]?.name || "a former player";
+        }
+        return "the GM";
+    }
+
+    // what a roll was for and how it turned out, e.g. "action, risky/standard: partial"
+    _roll_outcome(roll) {
+        if (!roll.kind || roll.kind.type == "plain") {
+            return "";
+        }
+        var outcome = roll.kind.type;
+        if (roll.kind.type == "action") {
+            outcome += ", " + roll.kind.position + "/" + roll.kind.effect;
+        }
+        outcome += ": " + roll.result;
+        if (roll.stress != null) {
+            outcome += roll.stress < 0 ? ", clears 1 stress" : ", " + roll.stress + " stress";
+        }
+        return " (" + outcome + ")";
+    }
+
     render() {
         return html`
             <div id="sidebar">
@@ -268,6 +310,34 @@ export class Sidebar extends LitElement {
                         <a onClick="roll_all_dice()" class="grow">roll all</a>
                         <a onClick="change_dice_colors()" class="shrink">&#x1F308;</a>
                     </div>
+                    <div class="dicerow">
+                        <select id="rollkind">
+                            <option value="plain">just dice</option>
+                            <option value="action">action</option>
+                            <option value="resistance">resistance</option>
+                            <option value="fortune">fortune</option>
+                            <option value="engagement">engagement</option>
+                        </select>
+                        <select id="position">
+                            <option value="controlled">controlled</option>
+                            <option value="risky" selected>risky</option>
+                            <option value="desperate">desperate</option>
+                        </select>
+                        <select id="effect">
+                            <option value="zero">zero</option>
+                            <option value="limited">limited</option>
+                            <option value="standard" selected>standard</option>
+                            <option value="great">great</option>
+                            <option value="extreme">extreme</option>
+                        </select>
+                    </div>
+                    <ul id="rolls">
+                        ${this._recent_rolls().map((roll) => html`
+                            <li title="${new Date(roll.time).toLocaleString()}">
+                                ${this._roller_name(roll.roller)} rolled ${roll.faces.join(" ")}${this._roll_outcome(roll)}
+                            </li>
+                        `)}
+                    </ul>
                 </div>
 
                 <div class="section">
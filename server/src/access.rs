@@ -0,0 +1,380 @@
+//! Who may do what in a campaign. Clients join with an invite token, which determines their role,
+//! and every instruction they send is checked against it.
+//!
+//! Invites are created by the GM, may expire, and can be revoked at any time. Only a hash of each
+//! token is kept, in the campaign's `invites.toml`. Campaigns are closed: nobody is their GM
+//! without a GM's invite, the first of which is made along with the campaign (or by the server's
+//! admin, for campaigns that have none).
+
+use crate::schema::Document;
+use crate::{persist, BitdError, Instruction, PlayerId, Result};
+use dashmap::DashMap;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub type InviteId = Uuid;
+
+/// Length of generated invite tokens.
+const TOKEN_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum Role {
+    /// Can do anything.
+    Gm,
+    /// Can manage their own player and clocks, and add to the map and notes.
+    Player { player_id: PlayerId },
+    /// Can only watch.
+    Spectator,
+}
+
+impl Role {
+    /// Check that this role may carry out an instruction.
+    pub fn authorize(&self, inst: &Instruction) -> Result<()> {
+        let allowed = match (self, inst) {
+            (Role::Gm, _) => true,
+            (_, Instruction::FullSync | Instruction::Resume { .. }) => true,
+            (Role::Spectator, _) => false,
+            // Players may only undo their own changes, which `Campaign::apply` checks when it takes
+            // them from the history.
+            (Role::Player { .. }, Instruction::Undo | Instruction::Redo) => true,
+            // Players only take stress and trauma by the rules.
+            (Role::Player { .. }, Instruction::EditSheet(_, field)) if field.follows_rules() => {
+                false
+            }
+            (
+                Role::Player { player_id },
+                Instruction::AddClock(owner, ..)
+                | Instruction::DeleteClock(owner, _)
+                | Instruction::IncrementClock(owner, _)
+                | Instruction::DecrementClock(owner, _)
+                | Instruction::RenamePlayer(owner, _)
+                | Instruction::EditSheet(owner, _)
+                | Instruction::PushYourself(owner)
+                | Instruction::ChooseTrauma(owner, _)
+                | Instruction::Roll {
+                    player_id: Some(owner),
+                    ..
+                },
+            ) => owner == player_id,
+            (
+                Role::Player { .. },
+                Instruction::AddLandmark(..)
+                | Instruction::AddNote(..)
+                | Instruction::EditNote(..)
+                | Instruction::RollDice(_)
+                | Instruction::Roll { .. },
+            ) => true,
+            (
+                Role::Player { .. },
+                Instruction::AddPlayer(_)
+                | Instruction::DeletePlayer(_)
+                | Instruction::DeleteLandmark(_)
+                | Instruction::DeleteNote(_)
+                | Instruction::CreateInvite { .. }
+                | Instruction::RevokeInvite(_)
+                | Instruction::ListInvites
+                | Instruction::ListTrash
+                | Instruction::RestoreTrash(_)
+                | Instruction::PurgeTrash(_)
+                | Instruction::EmptyTrash
+                | Instruction::RotateSeed,
+            ) => false,
+        };
+        if !allowed {
+            return Err(BitdError::Forbidden {
+                message: format!("{} may not do that.", self.describe()),
+            });
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Role::Gm => "The GM",
+            Role::Player { .. } => "Players",
+            Role::Spectator => "Spectators",
+        }
+    }
+}
+
+/// An invitation to join a campaign with a given role.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: InviteId,
+    #[serde(flatten)]
+    pub role: Role,
+    /// Who the invite is for, to tell invites apart when listing them.
+    pub label: String,
+    /// Unix time the invite was created.
+    pub created: u64,
+    /// Unix time after which the invite can no longer be used, if any.
+    pub expires: Option<u64>,
+}
+
+impl Invite {
+    fn expired(&self) -> bool {
+        self.expires.is_some_and(|expires| now() >= expires)
+    }
+}
+
+/// A campaign's invites, keyed by the hash of their token.
+#[derive(Debug)]
+pub struct Access {
+    invites: DashMap<String, Invite>,
+    path: PathBuf,
+}
+
+impl Access {
+    pub fn load(save_dir: &Path) -> Result<Self> {
+        let mut access = Access {
+            invites: DashMap::new(),
+            path: save_dir.join("invites.toml"),
+        };
+        if let Some(invites) =
+            persist::load::<HashMap<String, Invite>>(&access.path, Document::Invites)?
+        {
+            access.invites.extend(invites);
+        }
+        Ok(access)
+    }
+
+    fn save(&self) -> Result<()> {
+        persist::save(&self.path, &self.invites)
+    }
+
+    /// Check a token presented by a client joining the campaign, returning the invite it belongs
+    /// to. Clients without a token may only watch.
+    pub fn join(&self, token: Option<&str>) -> Result<Option<InviteId>> {
+        match token {
+            None => Ok(None),
+            Some(token) => match self.invites.get(&hash(token)) {
+                Some(invite) if !invite.expired() => Ok(Some(invite.id)),
+                _ => Err(BitdError::InvalidToken),
+            },
+        }
+    }
+
+    /// The current role of a client that joined with the given invite, or without one. Fails if
+    /// the invite has since been revoked or has expired.
+    pub fn role(&self, invite_id: Option<InviteId>) -> Result<Role> {
+        match invite_id {
+            None => Ok(Role::Spectator),
+            Some(id) => self
+                .invites
+                .iter()
+                .find(|invite| invite.id == id && !invite.expired())
+                .map(|invite| invite.role.clone())
+                .ok_or(BitdError::InvalidToken),
+        }
+    }
+
+    /// Check that a token presented outside of a websocket, e.g. to export the campaign, is a
+    /// GM's.
+    pub fn check_gm(&self, token: Option<&str>) -> Result<()> {
+        let role = self.role(self.join(token)?)?;
+        if role != Role::Gm {
+            return Err(BitdError::Forbidden {
+                message: format!("{} may not do that.", role.describe()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Create an invite, returning it along with its token. The token can't be recovered later.
+    pub fn create(
+        &self,
+        role: Role,
+        label: String,
+        expires_in: Option<u64>,
+    ) -> Result<(Invite, String)> {
+        if self.invites.is_empty() && role != Role::Gm {
+            return Err(BitdError::validation(
+                "role",
+                "The first invite must be for the GM, or they would be locked out.",
+            ));
+        }
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        let created = now();
+        let invite = Invite {
+            id: Uuid::now_v7(),
+            role,
+            label,
+            created,
+            expires: expires_in.map(|secs| created.saturating_add(secs)),
+        };
+        self.invites.insert(hash(&token), invite.clone());
+        self.save()?;
+        Ok((invite, token))
+    }
+
+    pub fn revoke(&self, id: InviteId) -> Result<()> {
+        let hash = self
+            .invites
+            .iter()
+            .find(|invite| invite.id == id)
+            .map(|invite| invite.key().clone())
+            .ok_or(BitdError::InviteLookup { invite_id: id })?;
+        let gm_invites = self.invites.iter().filter(|i| i.role == Role::Gm).count();
+        let revoking_gm = self.invites.get(&hash).is_some_and(|i| i.role == Role::Gm);
+        if revoking_gm && gm_invites == 1 {
+            return Err(BitdError::validation(
+                "invite",
+                "Cannot revoke the last GM invite, or the GM would be locked out.",
+            ));
+        }
+        self.invites.remove(&hash);
+        self.save()?;
+        Ok(())
+    }
+
+    /// Whether anyone can join the campaign as its GM.
+    pub fn has_gm(&self) -> bool {
+        self.invites.iter().any(|invite| invite.role == Role::Gm)
+    }
+
+    pub fn list(&self) -> Vec<Invite> {
+        let mut invites: Vec<Invite> = self.invites.iter().map(|i| i.value().clone()).collect();
+        invites.sort_by_key(|invite| invite.id);
+        invites
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Current unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::SheetField;
+    use crate::storage::tests::TempDir;
+    use crate::NoteCategory;
+
+    fn allowed(role: &Role, inst: Instruction) -> bool {
+        role.authorize(&inst).is_ok()
+    }
+
+    #[test]
+    fn roles_may_only_do_their_part() {
+        let me = Uuid::now_v7();
+        let them = Uuid::now_v7();
+        let player = Role::Player { player_id: me };
+
+        assert!(allowed(&Role::Gm, Instruction::AddPlayer("Bob".into())));
+        assert!(allowed(
+            &Role::Gm,
+            Instruction::EditSheet(them, SheetField::Stress(3))
+        ));
+        assert!(allowed(&Role::Gm, Instruction::RotateSeed));
+
+        assert!(allowed(
+            &player,
+            Instruction::RenamePlayer(me, "Bob".into())
+        ));
+        assert!(!allowed(
+            &player,
+            Instruction::RenamePlayer(them, "Bob".into())
+        ));
+        assert!(allowed(
+            &player,
+            Instruction::EditSheet(me, SheetField::Coin(2))
+        ));
+        assert!(!allowed(
+            &player,
+            Instruction::EditSheet(me, SheetField::Stress(0))
+        ));
+        assert!(!allowed(
+            &player,
+            Instruction::EditSheet(me, SheetField::Retired(false))
+        ));
+        assert!(allowed(
+            &player,
+            Instruction::AddNote("Lyssa".into(), String::new(), NoteCategory::Person)
+        ));
+        assert!(!allowed(&player, Instruction::AddPlayer("Bob".into())));
+        assert!(!allowed(&player, Instruction::ListInvites));
+        assert!(allowed(&player, Instruction::Undo));
+
+        assert!(allowed(&Role::Spectator, Instruction::FullSync));
+        assert!(allowed(&Role::Spectator, Instruction::Resume { since: 0 }));
+        assert!(!allowed(&Role::Spectator, Instruction::RollDice(2)));
+        assert!(!allowed(&Role::Spectator, Instruction::Undo));
+    }
+
+    #[test]
+    fn invites_decide_roles() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+        // Nobody is the GM of a campaign without invites.
+        assert_eq!(
+            access.role(access.join(None).unwrap()).unwrap(),
+            Role::Spectator
+        );
+        let player_id = Uuid::now_v7();
+        let player = Role::Player { player_id };
+        assert!(access.create(player.clone(), "Bob".into(), None).is_err());
+
+        let (gm, gm_token) = access.create(Role::Gm, "GM".into(), None).unwrap();
+        let (_, player_token) = access.create(player.clone(), "Bob".into(), None).unwrap();
+        assert_eq!(access.join(Some(&gm_token)).unwrap(), Some(gm.id));
+        let invite = access.join(Some(&player_token)).unwrap();
+        assert_eq!(access.role(invite).unwrap(), player);
+        assert!(matches!(
+            access.join(Some("not a token")),
+            Err(BitdError::InvalidToken)
+        ));
+        assert!(access.check_gm(Some(&gm_token)).is_ok());
+        assert!(access.check_gm(Some(&player_token)).is_err());
+        assert!(access.check_gm(None).is_err());
+
+        // Invites are kept, as hashes, when the campaign is loaded again.
+        let access = Access::load(&dir.0).unwrap();
+        assert_eq!(access.role(invite).unwrap(), player);
+        let saved = std::fs::read_to_string(dir.0.join("invites.toml")).unwrap();
+        assert!(!saved.contains(&player_token));
+    }
+
+    #[test]
+    fn expired_and_revoked_invites_stop_working() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+        let (gm, _) = access.create(Role::Gm, "GM".into(), None).unwrap();
+        let (_, expired) = access
+            .create(Role::Spectator, "Watcher".into(), Some(0))
+            .unwrap();
+        assert!(matches!(
+            access.join(Some(&expired)),
+            Err(BitdError::InvalidToken)
+        ));
+
+        let (spectator, token) = access
+            .create(Role::Spectator, "Watcher".into(), None)
+            .unwrap();
+        let joined = access.join(Some(&token)).unwrap();
+        access.revoke(spectator.id).unwrap();
+        // Clients that joined with it lose their role too.
+        assert!(matches!(access.role(joined), Err(BitdError::InvalidToken)));
+        assert!(access.join(Some(&token)).is_err());
+
+        // The GM can't be locked out.
+        assert!(access.revoke(gm.id).is_err());
+        assert!(access.has_gm());
+    }
+}
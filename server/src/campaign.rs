@@ -0,0 +1,1070 @@
+//! Campaigns hosted by the server. Each campaign has its own game state, save directory and
+//! broadcast channel, and is loaded from disk when the first client joins it.
+
+use crate::access::{Access, Invite, Role};
+use crate::dice::{Dice, Seed};
+use crate::events::{Cause, Event, EventLog};
+use crate::export::{Export, ImportMode};
+use crate::snapshot::{SnapshotInfo, Snapshots};
+use crate::storage::{self, Backend};
+use crate::{config::Config, Bitd, BitdError, Instruction, Outcome, Reply, Result, SyncRequest};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Campaign served at `/ws`, and the one any data saved before campaigns existed is moved to.
+pub const DEFAULT_CAMPAIGN: &str = "default";
+
+/// Longest allowed campaign name.
+const MAX_NAME_LEN: usize = 64;
+
+/// How often loaded campaigns purge what has been in their trash for too long.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A single game: its state and the clients playing it.
+pub struct Campaign {
+    name: String,
+    pub bitd: Bitd,
+    pub access: Access,
+    // Channel used to send messages to all connected clients.
+    tx: broadcast::Sender<Stamped>,
+    journal: Mutex<Journal>,
+    history: Mutex<History>,
+    /// Snapshots of the campaign's state, unless it is kept in memory.
+    snapshots: Option<Snapshots>,
+}
+
+impl Campaign {
+    fn load(name: &str, save_dir: PathBuf, config: &Config) -> Result<Self> {
+        let (tx, _rx) = broadcast::channel(config.broadcast_capacity);
+        let bitd = Bitd::new(
+            storage::open(config.storage, &save_dir)?,
+            Dice::load(&save_dir)?,
+            config.trash_retention_days,
+            config.roll_history,
+        )?;
+        let mut journal = Journal::new(config.resume_history);
+
+        // There's no point logging changes that won't be saved.
+        if config.storage != Backend::Memory {
+            let (mut log, unsaved) = EventLog::open(&save_dir, config.log_compact_after)?;
+            if let Some(last) = unsaved.last() {
+                journal.revision = journal.revision.max(last.rev);
+                for event in &unsaved {
+                    // Changes that no longer apply were undone by later ones anyway.
+                    bitd.replay(&event.change);
+                    bitd.save(&event.change)?;
+                }
+                tracing::info!(
+                    "replayed {} logged changes in {}",
+                    unsaved.len(),
+                    save_dir.display()
+                );
+            }
+            // Storage has caught up with everything logged so far.
+            log.compact()?;
+            journal.log = Some(log);
+        }
+        bitd.purge_expired()?;
+
+        let snapshots = (config.storage != Backend::Memory)
+            .then(|| Snapshots::new(&save_dir, config.snapshot_retention));
+        let campaign = Campaign {
+            name: name.to_string(),
+            access: Access::load(&save_dir)?,
+            bitd,
+            tx,
+            journal: Mutex::new(journal),
+            history: Mutex::new(History::new(config.undo_history)),
+            snapshots,
+        };
+        Ok(campaign)
+    }
+
+    /// Carry out an instruction a client sent, which `cause` records, and log, save and publish
+    /// the changes it makes.
+    ///
+    /// The history is held from making the changes until they're published, so that changes are
+    /// published (and logged) in the order they were made, and the history lists them in that
+    /// order too.
+    pub fn apply(&self, cause: Cause, inst: Instruction) -> Result<Applied> {
+        let mut history = self.history.lock().unwrap();
+        let role = cause.role.clone();
+        match self.bitd.apply(inst, &role, &self.access)? {
+            Outcome::Reply(reply) => Ok(Applied::Reply(reply)),
+            Outcome::Change {
+                change,
+                created,
+                undo,
+            } => {
+                let rev = self.commit(cause, change.clone())?;
+                if let Some(undo) = undo {
+                    history.push(Undoable {
+                        author: role,
+                        change,
+                        undo,
+                    });
+                }
+                Ok(Applied::Changed(created, Some(rev)))
+            }
+            // Changes made together are published one after another, with none in between. They
+            // can't be undone.
+            Outcome::Changes { changes, created } => {
+                let mut rev = None;
+                for change in changes {
+                    rev = Some(self.commit(cause.clone(), change)?);
+                }
+                Ok(Applied::Changed(created, rev))
+            }
+            Outcome::Undo => {
+                let mut entry = History::take(&mut history.undo, &role)
+                    .ok_or_else(|| BitdError::validation("undo", "There is nothing to undo."))?;
+                let (undo, rev) = self.reapply(cause, &entry.undo)?;
+                if let SyncRequest::Trash(item) = &undo {
+                    entry.change = SyncRequest::Restore(item.clone());
+                }
+                entry.undo = undo;
+                history.redo.push_back(entry);
+                Ok(Applied::Changed(None, Some(rev)))
+            }
+            Outcome::Redo => {
+                let mut entry = History::take(&mut history.redo, &role)
+                    .ok_or_else(|| BitdError::validation("redo", "There is nothing to redo."))?;
+                let (change, rev) = self.reapply(cause, &entry.change)?;
+                if let SyncRequest::Trash(item) = &change {
+                    entry.undo = SyncRequest::Restore(item.clone());
+                }
+                entry.change = change;
+                history.undo.push_back(entry);
+                Ok(Applied::Changed(None, Some(rev)))
+            }
+        }
+    }
+
+    /// Apply a change remembered in the history: the inverse of a change to undo it, or the change
+    /// itself to redo it.
+    ///
+    /// Returns the change as it was applied, and the revision it brought state to. Whatever it
+    /// moved to the trash is what the opposite of the history entry restores, so undo and redo
+    /// never bring back an outdated copy.
+    fn reapply(&self, cause: Cause, change: &SyncRequest) -> Result<(SyncRequest, u64)> {
+        let change = self.bitd.refresh(change, &cause.role);
+        if !self.bitd.replay(&change) {
+            return Err(BitdError::validation(
+                "undo",
+                "What this changed has since been removed, so it can't be undone or redone.",
+            ));
+        }
+        let rev = self.commit(cause, change.clone())?;
+        Ok((change, rev))
+    }
+
+    /// Log, save and publish a change made on behalf of a client.
+    ///
+    /// Once a change is logged it will be saved sooner or later: if saving it to storage fails,
+    /// it is replayed from the log when the campaign is next loaded, so it is published anyway.
+    /// If logging it fails, it's rolled back instead. Returns the revision it brought state to.
+    pub fn commit(&self, cause: Cause, change: SyncRequest) -> Result<u64> {
+        let mut journal = self.journal.lock().unwrap();
+        let rev = journal.revision + 1;
+        if let Some(log) = &mut journal.log {
+            if let Err(e) = log.append(&Event::new(rev, cause, change.clone())) {
+                // The change was already made in memory, but one that wasn't logged may never
+                // be saved, so it's taken back.
+                if let Err(e) = self.roll_back(log) {
+                    tracing::error!("could not roll back an unlogged change: {e}");
+                }
+                return Err(e);
+            }
+        }
+        match self.bitd.save(&change) {
+            Err(e) => {
+                tracing::error!(
+                    "could not save revision {rev}, which will be replayed from the log: {e}"
+                );
+                if let Some(log) = &mut journal.log {
+                    log.storage_behind();
+                }
+            }
+            // Imports are saved by replacing everything in storage, which catches up with any
+            // change that failed to save before.
+            Ok(()) if matches!(change, SyncRequest::Import(..)) => {
+                if let Some(log) = &mut journal.log {
+                    log.storage_caught_up();
+                }
+            }
+            Ok(()) => {}
+        }
+        let stamped = journal.record(change);
+        // Sending only fails if nobody is listening, which is fine.
+        let _ = self.tx.send(stamped);
+        if let Some(log) = &mut journal.log {
+            if let Err(e) = log.maybe_compact() {
+                tracing::warn!("could not compact the log: {e}");
+            }
+        }
+        Ok(rev)
+    }
+
+    /// Put the state in memory back to what was logged, after a change made in memory couldn't
+    /// be: what's saved in storage, along with any logged changes that failed to save.
+    fn roll_back(&self, log: &EventLog) -> Result<()> {
+        let unsaved = log.unsaved()?;
+        self.bitd.reload()?;
+        for event in &unsaved {
+            self.bitd.replay(&event.change);
+        }
+        Ok(())
+    }
+
+    /// Permanently delete anything that has been in the trash for longer than it is kept, logging
+    /// it like any other purge.
+    pub fn purge_expired(&self) -> Result<()> {
+        let _history = self.history.lock().unwrap();
+        let expired = self.bitd.expired_trash();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        tracing::info!(
+            "purging {} expired items from the trash of campaign {}",
+            expired.len(),
+            self.name
+        );
+        let purge = SyncRequest::Purge(expired);
+        self.bitd.replay(&purge);
+        self.commit(Cause::server(), purge)?;
+        Ok(())
+    }
+
+    /// Bring in an exported campaign. Changes from before the import can no longer be undone.
+    pub fn import(&self, export: Export, mode: ImportMode) -> Result<()> {
+        let mut history = self.history.lock().unwrap();
+        self.bitd.import(&export, mode);
+        self.commit(Cause::server(), SyncRequest::Import(Box::new(export), mode))?;
+        history.undo.clear();
+        history.redo.clear();
+        Ok(())
+    }
+
+    /// Take a snapshot of the campaign's state. Unless `force` is set, nothing is taken if the
+    /// state is the same as in the latest snapshot. Campaigns kept in memory have no snapshots.
+    pub fn snapshot(&self, force: bool) -> Result<Option<SnapshotInfo>> {
+        let Some(snapshots) = &self.snapshots else {
+            return Ok(None);
+        };
+        // Hold the history and the journal, as changes do, so that none is made while the state is
+        // being copied.
+        let _history = self.history.lock().unwrap();
+        let _journal = self.journal.lock().unwrap();
+        let taken = snapshots.take(&Export::new(&self.name, &self.bitd), force)?;
+        if let Some(snapshot) = &taken {
+            tracing::info!("took snapshot {} of campaign {}", snapshot.name, self.name);
+        }
+        Ok(taken)
+    }
+
+    /// Replace the campaign's state with that in a snapshot, sending every client a full sync.
+    /// The state being replaced is snapshotted first, so a restore can itself be undone.
+    pub fn restore(&self, snapshot: &str) -> Result<()> {
+        let snapshots = self
+            .snapshots
+            .as_ref()
+            .ok_or_else(|| BitdError::SnapshotLookup {
+                snapshot: snapshot.to_string(),
+            })?;
+        let export = snapshots.load(snapshot)?;
+        export.validate()?;
+        self.snapshot(false)?;
+        self.import(export, ImportMode::Replace)?;
+        tracing::info!("restored snapshot {snapshot} of campaign {}", self.name);
+        Ok(())
+    }
+
+    /// Stamp a sync request with the current revision.
+    pub fn stamp(&self, sync_req: SyncRequest) -> Stamped {
+        Stamped {
+            rev: self.journal.lock().unwrap().revision,
+            sync_req,
+        }
+    }
+
+    /// A full sync stamped with the current revision.
+    pub fn full(&self) -> Stamped {
+        self.stamp(SyncRequest::Full)
+    }
+
+    /// Everything a client that last saw revision `since` needs to catch up: the deltas it
+    /// missed, or a full sync if those are no longer available.
+    pub fn resume(&self, since: u64) -> Vec<Stamped> {
+        let missed = self.journal.lock().unwrap().since(since);
+        missed.unwrap_or_else(|| vec![self.full()])
+    }
+
+    /// Number of clients currently connected.
+    fn clients(&self) -> usize {
+        self.tx.receiver_count()
+    }
+}
+
+/// What carrying out an instruction came to.
+pub enum Applied {
+    /// Something to send back to the client that sent the instruction only.
+    Reply(Reply),
+    /// State changed, creating the entity with the given id, if any, and bringing state to the
+    /// given revision, unless nothing needed changing after all.
+    Changed(Option<Uuid>, Option<u64>),
+}
+
+/// A change that can be undone, and who made it.
+struct Undoable {
+    author: Role,
+    change: SyncRequest,
+    undo: SyncRequest,
+}
+
+/// Changes that can be undone, and changes that were undone and can be redone, oldest first.
+/// Only kept in memory, so they're forgotten when the campaign is unloaded.
+struct History {
+    undo: VecDeque<Undoable>,
+    redo: VecDeque<Undoable>,
+    capacity: usize,
+}
+
+impl History {
+    fn new(capacity: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, entry: Undoable) {
+        // A new change means the author's undone changes can't be redone any more.
+        self.redo.retain(|e| e.author != entry.author);
+        if self.undo.len() >= self.capacity {
+            self.undo.pop_front();
+        }
+        if self.capacity > 0 {
+            self.undo.push_back(entry);
+        }
+    }
+
+    /// Take the latest entry `role` may undo or redo: the GM may take anyone's, players only
+    /// their own.
+    fn take(entries: &mut VecDeque<Undoable>, role: &Role) -> Option<Undoable> {
+        let index = entries
+            .iter()
+            .rposition(|e| *role == Role::Gm || e.author == *role)?;
+        entries.remove(index)
+    }
+}
+
+/// A sync request along with the revision of state it brings a client up to.
+#[derive(Debug, Clone)]
+pub struct Stamped {
+    pub rev: u64,
+    pub sync_req: SyncRequest,
+}
+
+/// The current revision of state, a bounded history of the most recent deltas and the log every
+/// change is written to.
+struct Journal {
+    revision: u64,
+    recent: VecDeque<Stamped>,
+    capacity: usize,
+    log: Option<EventLog>,
+}
+
+impl Journal {
+    fn new(capacity: usize) -> Self {
+        // Start counting from the current time so that revisions keep increasing across server
+        // restarts, and a client resuming from before a restart can't mistake old revisions for
+        // new ones.
+        let revision = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Journal {
+            revision,
+            recent: VecDeque::with_capacity(capacity),
+            capacity,
+            log: None,
+        }
+    }
+
+    fn record(&mut self, sync_req: SyncRequest) -> Stamped {
+        self.revision += 1;
+        let stamped = Stamped {
+            rev: self.revision,
+            sync_req,
+        };
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        if self.capacity > 0 {
+            self.recent.push_back(stamped.clone());
+        }
+        stamped
+    }
+
+    /// The deltas after revision `rev`, or `None` if some of them have been forgotten (or `rev`
+    /// doesn't come from this server).
+    fn since(&self, rev: u64) -> Option<Vec<Stamped>> {
+        if rev == self.revision {
+            return Some(Vec::new());
+        }
+        match self.recent.front() {
+            // Checking `rev` first keeps `rev + 1` from overflowing on a bogus revision.
+            Some(oldest) if rev < self.revision && oldest.rev <= rev + 1 => Some(
+                self.recent
+                    .iter()
+                    .filter(|s| s.rev > rev)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Summary of a campaign, as listed by `GET /campaigns`.
+#[derive(Serialize, Debug)]
+pub struct CampaignInfo {
+    name: String,
+    archived: bool,
+    clients: usize,
+}
+
+/// Every campaign the server knows about. Active campaigns live in `{data_dir}/campaigns/{name}`
+/// and archived ones in `{data_dir}/archive/{name}`.
+pub struct Campaigns {
+    config: Config,
+    loaded: DashMap<String, Arc<Campaign>>,
+    /// Whether campaigns are being played, rather than only opened for a command. Campaigns being
+    /// played are snapshotted when loaded and on a schedule, and purge their trash on one.
+    serving: bool,
+}
+
+impl Campaigns {
+    /// The campaigns to serve, after moving data saved before campaigns existed into the default
+    /// campaign and creating it if needed.
+    pub fn new(config: Config) -> Self {
+        let campaigns = Campaigns {
+            serving: true,
+            ..Campaigns::for_command(config)
+        };
+
+        if let Err(e) = campaigns.migrate_legacy_data() {
+            tracing::warn!("could not move existing data into the default campaign: {e}");
+        }
+        if let Err(e) = campaigns.set_up_default() {
+            tracing::warn!("could not set up the default campaign: {e}");
+        }
+
+        campaigns
+    }
+
+    /// The campaigns for a command given on the command line, which only opens the campaign it
+    /// names.
+    pub fn for_command(config: Config) -> Self {
+        Campaigns {
+            config,
+            loaded: DashMap::new(),
+            serving: false,
+        }
+    }
+
+    fn active_dir(&self, name: &str) -> PathBuf {
+        self.config.data_dir.join("campaigns").join(name)
+    }
+
+    fn archive_dir(&self, name: &str) -> PathBuf {
+        self.config.data_dir.join("archive").join(name)
+    }
+
+    /// Data saved before campaigns existed lives directly in the data directory. Move it to the
+    /// default campaign so that it isn't lost.
+    fn migrate_legacy_data(&self) -> Result<()> {
+        let default_dir = self.active_dir(DEFAULT_CAMPAIGN);
+        if default_dir.exists() {
+            return Ok(());
+        }
+        for entry in ["players", "landmarks.toml", "notes.toml"] {
+            let legacy = self.config.data_dir.join(entry);
+            if legacy.exists() {
+                fs::create_dir_all(&default_dir)?;
+                fs::rename(&legacy, default_dir.join(entry))?;
+                tracing::info!(
+                    "moved {} into the default campaign at {}",
+                    legacy.display(),
+                    default_dir.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a campaign for a new client, loading it from disk if nobody is playing it yet. Also
+    /// returns a subscription to the campaign's broadcast channel, which keeps the campaign
+    /// loaded until it is dropped and [`Campaigns::leave`] is called.
+    pub fn join(&self, name: &str) -> Result<(Arc<Campaign>, broadcast::Receiver<Stamped>)> {
+        validate_name(name)?;
+        // Subscribe while holding the entry so that `leave` can't unload the campaign between
+        // loading it and subscribing.
+        let campaign = match self.loaded.entry(name.to_string()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                let dir = self.active_dir(name);
+                if !dir.is_dir() {
+                    return Err(BitdError::CampaignLookup {
+                        campaign: name.to_string(),
+                    });
+                }
+                tracing::info!("loading campaign {name}");
+                let campaign = Campaign::load(name, dir, &self.config).inspect_err(|e| {
+                    tracing::error!("could not load campaign {name}: {e}");
+                })?;
+                let campaign = Arc::new(campaign);
+                if self.serving {
+                    // Every session starts with a snapshot, in case it goes badly.
+                    if let Err(e) = campaign.snapshot(false) {
+                        tracing::warn!("could not take a snapshot of campaign {name}: {e}");
+                    }
+                    if self.config.snapshot_interval_minutes > 0 {
+                        schedule_snapshots(&campaign, self.config.snapshot_interval_minutes);
+                    }
+                    if self.config.trash_retention_days > 0 {
+                        schedule_purges(&campaign);
+                    }
+                }
+                entry.insert(campaign)
+            }
+        };
+        let rx = campaign.tx.subscribe();
+        Ok((campaign.clone(), rx))
+    }
+
+    /// Unload a campaign if its last client has left. State is saved as it changes, so there's
+    /// nothing else to do.
+    pub fn leave(&self, name: &str) {
+        if self
+            .loaded
+            .remove_if(name, |_, campaign| campaign.clients() == 0)
+            .is_some()
+        {
+            tracing::info!("unloaded idle campaign {name}");
+        }
+    }
+
+    /// Do something with a campaign, loading it for as long as that takes if need be.
+    fn with<T>(&self, name: &str, f: impl FnOnce(&Campaign) -> Result<T>) -> Result<T> {
+        let (campaign, rx) = self.join(name)?;
+        let result = f(&campaign);
+        drop(rx);
+        self.leave(name);
+        result
+    }
+
+    /// Whether `token` is the server's admin token.
+    fn is_admin(&self, token: Option<&str>) -> bool {
+        // Compare hashes so that how long the comparison takes says nothing about the token.
+        match (&self.config.admin_token, token) {
+            (Some(admin), Some(token)) => Sha256::digest(admin) == Sha256::digest(token),
+            _ => false,
+        }
+    }
+
+    /// Check that `token` may list and create campaigns: it must be the server's admin token, if
+    /// one is set. Without one, anyone may.
+    pub fn authorize_admin(&self, token: Option<&str>) -> Result<()> {
+        match self.config.admin_token {
+            None => Ok(()),
+            Some(_) => self.require_admin(token),
+        }
+    }
+
+    /// Check that `token` is the server's admin token, for what nobody may do without one set.
+    pub fn require_admin(&self, token: Option<&str>) -> Result<()> {
+        match token {
+            _ if self.is_admin(token) => Ok(()),
+            Some(_) => Err(BitdError::InvalidToken),
+            None => Err(BitdError::Forbidden {
+                message: "Only the server's admin may manage campaigns.".to_string(),
+            }),
+        }
+    }
+
+    /// Check that `token` is a GM's invite to a campaign, or the server's admin token. Campaigns
+    /// without invites are open, so anyone is their GM. Campaigns that don't exist yet are only
+    /// created by the admin.
+    pub fn authorize_gm(&self, name: &str, token: Option<&str>) -> Result<()> {
+        validate_name(name)?;
+        if self.is_admin(token) {
+            return Ok(());
+        }
+        if !self.active_dir(name).is_dir() {
+            return self.authorize_admin(token);
+        }
+        match self.loaded.get(name) {
+            Some(campaign) => campaign.access.check_gm(token),
+            None => Access::load(&self.active_dir(name))?.check_gm(token),
+        }
+    }
+
+    /// Check a token a client wants to join a campaign with, without loading the campaign, so that
+    /// clients with bad tokens can't make it load.
+    pub fn check_token(&self, name: &str, token: Option<&str>) -> Result<()> {
+        validate_name(name)?;
+        if let Some(campaign) = self.loaded.get(name) {
+            return campaign.access.join(token).map(|_| ());
+        }
+        let dir = self.active_dir(name);
+        if !dir.is_dir() {
+            return Err(BitdError::CampaignLookup {
+                campaign: name.to_string(),
+            });
+        }
+        Access::load(&dir)?.join(token).map(|_| ())
+    }
+
+    pub fn export(&self, name: &str) -> Result<Export> {
+        self.with(name, |campaign| Ok(Export::new(name, &campaign.bitd)))
+    }
+
+    /// Every seed a campaign has rolled from, to check its rolls with.
+    pub fn seeds(&self, name: &str) -> Result<Vec<Seed>> {
+        self.with(name, |campaign| Ok(campaign.bitd.dice.seeds()))
+    }
+
+    /// Import an exported campaign, creating the campaign if it doesn't exist yet. Anyone playing
+    /// it is sent the new state.
+    pub fn import(&self, name: &str, export: Export, mode: ImportMode) -> Result<()> {
+        validate_name(name)?;
+        export.validate()?;
+        if !self.active_dir(name).exists() {
+            self.create(name)?;
+        }
+        self.with(name, |campaign| campaign.import(export, mode))?;
+        tracing::info!("imported into campaign {name}");
+        Ok(())
+    }
+
+    /// A campaign's snapshots, newest first.
+    pub fn snapshots(&self, name: &str) -> Result<Vec<SnapshotInfo>> {
+        self.with(name, |campaign| match &campaign.snapshots {
+            Some(snapshots) => snapshots.list(),
+            None => Ok(Vec::new()),
+        })
+    }
+
+    /// Take a snapshot of a campaign now, even if nothing changed since the last one.
+    pub fn take_snapshot(&self, name: &str) -> Result<SnapshotInfo> {
+        self.with(name, |campaign| {
+            campaign.snapshot(true)?.ok_or_else(|| {
+                BitdError::validation("storage", "Campaigns kept in memory have no snapshots.")
+            })
+        })
+    }
+
+    pub fn restore_snapshot(&self, name: &str, snapshot: &str) -> Result<()> {
+        self.with(name, |campaign| campaign.restore(snapshot))
+    }
+
+    pub fn list(&self) -> Result<Vec<CampaignInfo>> {
+        let mut campaigns = Vec::new();
+        for (archived, dir) in [
+            (false, self.config.data_dir.join("campaigns")),
+            (true, self.config.data_dir.join("archive")),
+        ] {
+            if !dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().to_string();
+                let clients = match archived {
+                    true => 0,
+                    false => self.loaded.get(&name).map_or(0, |c| c.clients()),
+                };
+                campaigns.push(CampaignInfo {
+                    name,
+                    archived,
+                    clients,
+                });
+            }
+        }
+        campaigns.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(campaigns)
+    }
+
+    /// Create a campaign, returning the invite for its GM and the invite's token.
+    pub fn create(&self, name: &str) -> Result<(Invite, String)> {
+        validate_name(name)?;
+        let dir = self.active_dir(name);
+        if dir.exists() || self.archive_dir(name).exists() {
+            return Err(BitdError::CampaignExists {
+                campaign: name.to_string(),
+            });
+        }
+        fs::create_dir_all(dir.join("players"))?;
+        tracing::info!("created campaign {name}");
+        self.invite_gm(name, "created with the campaign")
+    }
+
+    /// Create another invite for a campaign's GM, returning it along with its token.
+    pub fn invite_gm(&self, name: &str, label: &str) -> Result<(Invite, String)> {
+        validate_name(name)?;
+        // Hold the entry so that the campaign can't be loaded with the invites from before this
+        // one is saved.
+        match self.loaded.entry(name.to_string()) {
+            Entry::Occupied(entry) => entry.get().access.create(Role::Gm, label.into(), None),
+            Entry::Vacant(_) => {
+                let dir = self.active_dir(name);
+                if !dir.is_dir() {
+                    return Err(BitdError::CampaignLookup {
+                        campaign: name.to_string(),
+                    });
+                }
+                Access::load(&dir)?.create(Role::Gm, label.into(), None)
+            }
+        }
+    }
+
+    /// Create the default campaign if it doesn't exist, and invite a GM to it if nobody can run
+    /// it yet, logging how they can join.
+    fn set_up_default(&self) -> Result<()> {
+        let (_, token) = if !self.active_dir(DEFAULT_CAMPAIGN).exists() {
+            self.create(DEFAULT_CAMPAIGN)?
+        } else if Access::load(&self.active_dir(DEFAULT_CAMPAIGN))?.has_gm() {
+            return Ok(());
+        } else {
+            self.invite_gm(DEFAULT_CAMPAIGN, "first GM")?
+        };
+        tracing::info!("the GM of the default campaign can join with /?token={token}");
+        Ok(())
+    }
+
+    /// Move a campaign to the archive. Campaigns can only be archived while nobody is playing
+    /// them.
+    pub fn archive(&self, name: &str) -> Result<()> {
+        validate_name(name)?;
+        let dir = self.active_dir(name);
+        if !dir.is_dir() {
+            return Err(BitdError::CampaignLookup {
+                campaign: name.to_string(),
+            });
+        }
+        if self.archive_dir(name).exists() {
+            return Err(BitdError::CampaignExists {
+                campaign: name.to_string(),
+            });
+        }
+        // Hold the entry so nobody can join while the files are being moved.
+        let entry = self.loaded.entry(name.to_string());
+        if let Entry::Occupied(ref loaded) = entry {
+            if loaded.get().clients() > 0 {
+                return Err(BitdError::CampaignInUse {
+                    campaign: name.to_string(),
+                });
+            }
+        }
+        fs::create_dir_all(self.config.data_dir.join("archive"))?;
+        fs::rename(&dir, self.archive_dir(name))?;
+        if let Entry::Occupied(loaded) = entry {
+            loaded.remove();
+        }
+        tracing::info!("archived campaign {name}");
+        Ok(())
+    }
+}
+
+/// Snapshot a campaign every `minutes` for as long as it stays loaded.
+fn schedule_snapshots(campaign: &Arc<Campaign>, minutes: u64) {
+    let campaign = Arc::downgrade(campaign);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(minutes * 60));
+        // The first tick is immediate, and a snapshot was just taken when the campaign loaded.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(campaign) = campaign.upgrade() else {
+                break;
+            };
+            if let Err(e) = campaign.snapshot(false) {
+                tracing::warn!(
+                    "could not take a snapshot of campaign {}: {e}",
+                    campaign.name
+                );
+            }
+        }
+    });
+}
+
+/// Purge expired items from a campaign's trash every [`PURGE_INTERVAL`] for as long as it stays
+/// loaded.
+fn schedule_purges(campaign: &Arc<Campaign>) {
+    let campaign = Arc::downgrade(campaign);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        // The first tick is immediate, and the trash was just purged when the campaign loaded.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(campaign) = campaign.upgrade() else {
+                break;
+            };
+            if let Err(e) = campaign.purge_expired() {
+                tracing::warn!(
+                    "could not purge the trash of campaign {}: {e}",
+                    campaign.name
+                );
+            }
+        }
+    });
+}
+
+/// Campaign names end up in paths and URLs, so keep them simple.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(BitdError::validation(
+            "campaign",
+            &format!(
+                "Campaign names must be 1 to {MAX_NAME_LEN} letters, digits, dashes or underscores."
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::storage::tests::TempDir;
+    use crate::trash::Trashed;
+    use crate::{Landmark, NoteCategory, PlayerData};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn loading_replays_logged_changes_that_were_not_saved() {
+        let dir = TempDir::new();
+        let config = Config::default();
+        let player_id = Uuid::now_v7();
+        let landmark_id = Uuid::now_v7();
+        let changes = [
+            SyncRequest::AddPlayer(player_id, PlayerData::new("Bob".into())),
+            SyncRequest::AddLandmark(
+                landmark_id,
+                Landmark {
+                    name: "Crow's Foot".into(),
+                    x: 0.0,
+                    y: 0.0,
+                },
+            ),
+        ];
+        // As left by a server that logged the changes but stopped before saving them, and before
+        // it finished logging one more. They're from later than now, as if the clock was turned
+        // back since.
+        let first = u64::MAX / 2;
+        let (mut log, _) = EventLog::open(&dir.0, config.log_compact_after).unwrap();
+        for (rev, change) in (first..).zip(changes) {
+            log.append(&Event::new(rev, Cause::server(), change))
+                .unwrap();
+        }
+        drop(log);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join("log").join("current.jsonl"))
+            .unwrap();
+        file.write_all(br#"{"version":1,"re"#).unwrap();
+        drop(file);
+
+        let campaign = Campaign::load("test", dir.0.clone(), &config).unwrap();
+        assert!(campaign.bitd.players.contains_key(&player_id));
+        assert!(campaign.bitd.landmarks.contains_key(&landmark_id));
+        // Revisions carry on from the last one logged.
+        assert_eq!(campaign.journal.lock().unwrap().revision, first + 1);
+        drop(campaign);
+
+        // The replayed changes were saved, so the log was compacted and loading again finds them
+        // in storage.
+        let (_, unsaved) = EventLog::open(&dir.0, config.log_compact_after).unwrap();
+        assert!(unsaved.is_empty());
+        let campaign = Campaign::load("test", dir.0.clone(), &config).unwrap();
+        assert!(campaign.bitd.players.contains_key(&player_id));
+        assert!(campaign.bitd.landmarks.contains_key(&landmark_id));
+    }
+
+    #[test]
+    fn undoing_a_creation_moves_it_to_the_trash_as_it_is_now() {
+        let dir = TempDir::new();
+        let campaign = Campaign::load("test", dir.0.clone(), &Config::default()).unwrap();
+        let player = Cause {
+            connection: None,
+            role: Role::Player {
+                player_id: Uuid::now_v7(),
+            },
+            instruction: None,
+        };
+        let add = Instruction::AddNote("Lyssa".into(), String::new(), NoteCategory::Person);
+        let Applied::Changed(Some(note_id), _) = campaign.apply(player.clone(), add).unwrap()
+        else {
+            panic!("adding a note didn't create one");
+        };
+        let edit = Instruction::EditNote(
+            note_id,
+            "Lyssa".into(),
+            "Leads the Crows.".into(),
+            NoteCategory::Person,
+        );
+        campaign.apply(Cause::server(), edit).unwrap();
+
+        // The player undoes their own change, which is adding the note, not the GM's edit.
+        campaign.apply(player.clone(), Instruction::Undo).unwrap();
+        assert!(!campaign.bitd.notes.contains_key(&note_id));
+        let item = campaign.bitd.trash.get(&note_id).unwrap().clone();
+        assert!(matches!(item.entity, Trashed::Note { note } if note.desc == "Leads the Crows."));
+
+        campaign.apply(player, Instruction::Redo).unwrap();
+        assert!(!campaign.bitd.trash.contains_key(&note_id));
+        assert_eq!(
+            campaign.bitd.notes.get(&note_id).unwrap().desc,
+            "Leads the Crows."
+        );
+    }
+
+    #[test]
+    fn changes_that_were_not_logged_are_rolled_back() {
+        let dir = TempDir::new();
+        let campaign = Campaign::load("test", dir.0.clone(), &Config::default()).unwrap();
+        let add = |name: &str| {
+            let inst = Instruction::AddPlayer(name.into());
+            let Applied::Changed(Some(id), _) = campaign.apply(Cause::server(), inst).unwrap()
+            else {
+                panic!("adding a player didn't create one");
+            };
+            id
+        };
+        let logged = add("Bob");
+        // As if the next change couldn't be logged.
+        let Outcome::Change { created, .. } = campaign
+            .bitd
+            .apply(
+                Instruction::AddPlayer("Alice".into()),
+                &Role::Gm,
+                &campaign.access,
+            )
+            .unwrap()
+        else {
+            panic!("adding a player changed nothing");
+        };
+
+        let journal = campaign.journal.lock().unwrap();
+        campaign.roll_back(journal.log.as_ref().unwrap()).unwrap();
+        assert!(campaign.bitd.players.contains_key(&logged));
+        assert!(!campaign.bitd.players.contains_key(&created.unwrap()));
+    }
+
+    #[test]
+    fn restoring_a_snapshot_brings_back_its_state() {
+        let dir = TempDir::new();
+        let config = Config::default();
+        let campaign = Campaign::load("test", dir.0.clone(), &config).unwrap();
+        let add = |name: &str| {
+            let inst = Instruction::AddPlayer(name.into());
+            let Applied::Changed(Some(id), _) = campaign.apply(Cause::server(), inst).unwrap()
+            else {
+                panic!("adding a player didn't create one");
+            };
+            id
+        };
+        let bob = add("Bob");
+        let taken = campaign.snapshot(true).unwrap().unwrap();
+        let alice = add("Alice");
+        campaign
+            .apply(Cause::server(), Instruction::DeletePlayer(bob))
+            .unwrap();
+
+        campaign.restore(&taken.name).unwrap();
+        assert!(campaign.bitd.players.contains_key(&bob));
+        assert!(!campaign.bitd.players.contains_key(&alice));
+        assert!(!campaign.bitd.trash.contains_key(&bob));
+        // The state that was replaced was snapshotted first.
+        let snapshots = campaign.snapshots.as_ref().unwrap().list().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        drop(campaign);
+
+        let campaign = Campaign::load("test", dir.0.clone(), &config).unwrap();
+        assert!(campaign.bitd.players.contains_key(&bob));
+        assert!(!campaign.bitd.players.contains_key(&alice));
+    }
+
+    #[test]
+    fn journal_gives_back_only_the_deltas_it_still_has() {
+        let mut journal = Journal::new(2);
+        let start = journal.revision;
+        let revs: Vec<u64> = (0..3)
+            .map(|_| journal.record(SyncRequest::Full).rev)
+            .collect();
+        assert_eq!(revs, [start + 1, start + 2, start + 3]);
+
+        let latest = |since| {
+            journal
+                .since(since)
+                .map(|missed| missed.iter().map(|s| s.rev).collect::<Vec<_>>())
+        };
+        assert_eq!(latest(start + 3), Some(vec![]));
+        assert_eq!(latest(start + 2), Some(vec![start + 3]));
+        assert_eq!(latest(start + 1), Some(vec![start + 2, start + 3]));
+        // The first delta was forgotten to make room.
+        assert_eq!(latest(start), None);
+        // Revisions from the future, or from another server, can't be resumed from.
+        assert_eq!(latest(start + 4), None);
+        assert_eq!(latest(u64::MAX), None);
+
+        let empty = Journal::new(0);
+        assert!(empty
+            .since(empty.revision)
+            .is_some_and(|missed| missed.is_empty()));
+        assert!(empty.since(empty.revision - 1).is_none());
+    }
+
+    #[test]
+    fn resuming_catches_up_with_the_changes_that_were_missed() {
+        let dir = TempDir::new();
+        let campaign = Campaign::load("test", dir.0.clone(), &Config::default()).unwrap();
+        let seen = campaign.full().rev;
+        let add = Instruction::AddPlayer("Bob".into());
+        let Applied::Changed(Some(bob), Some(rev)) = campaign.apply(Cause::server(), add).unwrap()
+        else {
+            panic!("adding a player didn't create one");
+        };
+
+        let missed = campaign.resume(seen);
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].rev, rev);
+        assert!(matches!(missed[0].sync_req, SyncRequest::AddPlayer(id, _) if id == bob));
+        assert!(campaign.resume(rev).is_empty());
+
+        let resynced = campaign.resume(u64::MAX);
+        assert_eq!(resynced.len(), 1);
+        assert_eq!(resynced[0].rev, rev);
+        assert!(matches!(resynced[0].sync_req, SyncRequest::Full));
+    }
+}
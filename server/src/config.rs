@@ -0,0 +1,280 @@
+//! Server configuration. Each setting is taken from the first of these that provides it:
+//! command-line flags, environment variables, the config file (`bitd.toml` by default), and
+//! finally the built-in defaults.
+
+use crate::export::ImportMode;
+use crate::storage::Backend;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Config file read when no other is given.
+const DEFAULT_CONFIG_FILE: &str = "bitd.toml";
+
+/// Settings as given on the command line, in the environment or in the config file, any of which
+/// may be missing.
+#[derive(Parser, Deserialize, Debug, Default)]
+#[command(about = "Server for the Blades in the Dark HUD")]
+#[serde(default, deny_unknown_fields)]
+struct Options {
+    /// Config file to read settings from [default: bitd.toml]
+    #[arg(long, env = "BITD_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, env = "BITD_BIND")]
+    bind: Option<IpAddr>,
+    /// Port to listen on [default: 3000]
+    #[arg(long, env = "BITD_PORT")]
+    port: Option<u16>,
+    /// Directory game state is saved in [default: ./data]
+    #[arg(long, env = "BITD_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Directory the client is served from [default: ../client]
+    #[arg(long, env = "BITD_STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// Log filter, e.g. `info` or `bitd_server=debug` [default: bitd_server=info]
+    #[arg(long, env = "BITD_LOG")]
+    log_level: Option<String>,
+    /// Number of updates buffered for slow clients before they are sent a full resync instead
+    /// [default: 100]
+    #[arg(long, env = "BITD_BROADCAST_CAPACITY")]
+    broadcast_capacity: Option<usize>,
+    /// Number of replies (full syncs, acknowledgements, ...) buffered per client [default: 16]
+    #[arg(long, env = "BITD_REPLY_CAPACITY")]
+    reply_capacity: Option<usize>,
+    /// Number of recent updates kept for reconnecting clients to catch up on [default: 1000]
+    #[arg(long, env = "BITD_RESUME_HISTORY")]
+    resume_history: Option<usize>,
+    /// Number of changes per campaign that can be undone [default: 100]
+    #[arg(long, env = "BITD_UNDO_HISTORY")]
+    undo_history: Option<usize>,
+    /// Number of changes logged before a campaign's log is compacted [default: 1000]
+    #[arg(long, env = "BITD_LOG_COMPACT_AFTER")]
+    log_compact_after: Option<usize>,
+    /// Days deleted things are kept in a campaign's trash, or 0 to keep them forever [default: 30]
+    #[arg(long, env = "BITD_TRASH_RETENTION_DAYS")]
+    trash_retention_days: Option<u64>,
+    /// Minutes between snapshots of each campaign being played, or 0 to only take one when a
+    /// campaign is loaded [default: 60]
+    #[arg(long, env = "BITD_SNAPSHOT_INTERVAL_MINUTES")]
+    snapshot_interval_minutes: Option<u64>,
+    /// Number of snapshots kept per campaign, or 0 to keep them all [default: 24]
+    #[arg(long, env = "BITD_SNAPSHOT_RETENTION")]
+    snapshot_retention: Option<usize>,
+    /// Number of recent dice rolls kept per campaign [default: 100]
+    #[arg(long, env = "BITD_ROLL_HISTORY")]
+    roll_history: Option<usize>,
+    /// How campaigns are saved [default: toml]
+    #[arg(long, env = "BITD_STORAGE", value_enum)]
+    storage: Option<Backend>,
+    /// PEM file with the TLS certificate chain. Serves HTTPS when given along with `tls_key`
+    #[arg(long, env = "BITD_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the TLS private key
+    #[arg(long, env = "BITD_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Port to redirect plain HTTP requests to HTTPS from, when serving HTTPS
+    #[arg(long, env = "BITD_REDIRECT_PORT")]
+    redirect_port: Option<u16>,
+    /// Secret needed to list, create and archive campaigns, which anyone can do without one
+    #[arg(long, env = "BITD_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+/// Things to do instead of serving. These work on the data directory directly, so should only be
+/// run while the server is stopped.
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Export a campaign to a JSON file
+    Export { campaign: String, file: PathBuf },
+    /// Import a campaign from a JSON file, creating the campaign if it doesn't exist
+    Import {
+        campaign: String,
+        file: PathBuf,
+        /// Whether to merge the import into what the campaign has, or replace it
+        #[arg(long, value_enum, default_value_t)]
+        mode: ImportMode,
+    },
+    /// Roll every roll in a campaign's history again from its seed, to check that it's genuine
+    VerifyRolls { campaign: String },
+}
+
+impl Options {
+    /// Fill in any settings missing from `self` with those from `other`.
+    fn or(self, other: Options) -> Options {
+        Options {
+            config: self.config.or(other.config),
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            data_dir: self.data_dir.or(other.data_dir),
+            static_dir: self.static_dir.or(other.static_dir),
+            log_level: self.log_level.or(other.log_level),
+            broadcast_capacity: self.broadcast_capacity.or(other.broadcast_capacity),
+            reply_capacity: self.reply_capacity.or(other.reply_capacity),
+            resume_history: self.resume_history.or(other.resume_history),
+            undo_history: self.undo_history.or(other.undo_history),
+            log_compact_after: self.log_compact_after.or(other.log_compact_after),
+            trash_retention_days: self.trash_retention_days.or(other.trash_retention_days),
+            snapshot_interval_minutes: self
+                .snapshot_interval_minutes
+                .or(other.snapshot_interval_minutes),
+            snapshot_retention: self.snapshot_retention.or(other.snapshot_retention),
+            roll_history: self.roll_history.or(other.roll_history),
+            storage: self.storage.or(other.storage),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            redirect_port: self.redirect_port.or(other.redirect_port),
+            admin_token: self.admin_token.or(other.admin_token),
+            command: self.command.or(other.command),
+        }
+    }
+}
+
+/// The settings the server runs with.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub static_dir: PathBuf,
+    pub log_level: String,
+    pub broadcast_capacity: usize,
+    pub reply_capacity: usize,
+    pub resume_history: usize,
+    pub undo_history: usize,
+    pub log_compact_after: usize,
+    pub trash_retention_days: u64,
+    pub snapshot_interval_minutes: u64,
+    pub snapshot_retention: usize,
+    pub roll_history: usize,
+    pub storage: Backend,
+    pub tls: Option<Tls>,
+    pub redirect_port: Option<u16>,
+    pub admin_token: Option<String>,
+    pub command: Option<Command>,
+}
+
+/// Certificate and key to serve HTTPS with.
+#[derive(Clone, Debug)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3000,
+            data_dir: "./data".into(),
+            static_dir: "../client".into(),
+            log_level: "bitd_server=info".into(),
+            broadcast_capacity: 100,
+            reply_capacity: 16,
+            resume_history: 1000,
+            undo_history: 100,
+            log_compact_after: 1000,
+            trash_retention_days: 30,
+            snapshot_interval_minutes: 60,
+            snapshot_retention: 24,
+            roll_history: 100,
+            storage: Backend::default(),
+            tls: None,
+            redirect_port: None,
+            admin_token: None,
+            command: None,
+        }
+    }
+}
+
+impl Config {
+    /// Gather settings from the command line, environment and config file.
+    pub fn load() -> Result<Self> {
+        let args = Options::parse();
+
+        // A missing config file is only a problem if one was asked for explicitly.
+        let file = match &args.config {
+            Some(path) => Self::read_file(path)?,
+            None => {
+                let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if path.exists() {
+                    Self::read_file(&path)?
+                } else {
+                    Options::default()
+                }
+            }
+        };
+
+        let options = args.or(file);
+        let defaults = Config::default();
+        let tls = match (options.tls_cert, options.tls_key) {
+            (Some(cert), Some(key)) => Some(Tls { cert, key }),
+            (None, None) => None,
+            _ => anyhow::bail!("tls_cert and tls_key must be given together"),
+        };
+        anyhow::ensure!(
+            tls.is_some() || options.redirect_port.is_none(),
+            "redirect_port only makes sense when serving HTTPS"
+        );
+        let config = Config {
+            bind: options.bind.unwrap_or(defaults.bind),
+            port: options.port.unwrap_or(defaults.port),
+            data_dir: options.data_dir.unwrap_or(defaults.data_dir),
+            static_dir: options.static_dir.unwrap_or(defaults.static_dir),
+            log_level: options.log_level.unwrap_or(defaults.log_level),
+            broadcast_capacity: options
+                .broadcast_capacity
+                .unwrap_or(defaults.broadcast_capacity),
+            reply_capacity: options.reply_capacity.unwrap_or(defaults.reply_capacity),
+            resume_history: options.resume_history.unwrap_or(defaults.resume_history),
+            undo_history: options.undo_history.unwrap_or(defaults.undo_history),
+            log_compact_after: options
+                .log_compact_after
+                .unwrap_or(defaults.log_compact_after),
+            trash_retention_days: options
+                .trash_retention_days
+                .unwrap_or(defaults.trash_retention_days),
+            snapshot_interval_minutes: options
+                .snapshot_interval_minutes
+                .unwrap_or(defaults.snapshot_interval_minutes),
+            snapshot_retention: options
+                .snapshot_retention
+                .unwrap_or(defaults.snapshot_retention),
+            roll_history: options.roll_history.unwrap_or(defaults.roll_history),
+            storage: options.storage.unwrap_or(defaults.storage),
+            tls,
+            redirect_port: options.redirect_port,
+            admin_token: options.admin_token,
+            command: options.command,
+        };
+        anyhow::ensure!(
+            config.broadcast_capacity > 0 && config.reply_capacity > 0,
+            "Channel capacities must be at least 1"
+        );
+        Ok(config)
+    }
+
+    fn read_file(path: &Path) -> Result<Options> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Could not parse config file {}", path.display()))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Address plain HTTP requests are redirected to HTTPS from, if enabled.
+    pub fn redirect_addr(&self) -> Option<SocketAddr> {
+        self.redirect_port
+            .map(|port| SocketAddr::new(self.bind, port))
+    }
+}
@@ -0,0 +1,423 @@
+//! Dice rolled by the server, so that everyone at the table sees the same roll and nobody can
+//! fudge it, and resolved by the rules for the kind of roll it was.
+//!
+//! Each campaign rolls from a secret seed, kept with the number of rolls made from it in the
+//! campaign's `dice.toml`. Every roll records a commitment to the seed (its SHA-256 hash) and its
+//! nonce, the number of rolls made from the seed before it. The seed itself is only revealed when
+//! the GM rotates it, so nobody can work out rolls before they're made, but once it's revealed
+//! anyone can roll the same dice again and check the rolls made from it.
+
+use crate::access::Role;
+use crate::schema::Document;
+use crate::{persist, BitdError, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Most dice that can be rolled at once.
+pub const MAX_POOL: u8 = 10;
+
+pub type RollId = Uuid;
+
+/// A secret seed, too big to guess. Saved and sent as hex.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Seed(#[serde(with = "hex::serde")] [u8; 32]);
+
+impl Seed {
+    fn random() -> Self {
+        Seed(rand::thread_rng().gen())
+    }
+
+    /// The hash of the seed, which rolls record so that they can be checked once it's revealed.
+    pub fn commitment(&self) -> String {
+        hex::encode(Sha256::digest(self.0))
+    }
+
+    /// Roll `dice` six-sided dice. Each nonce gets its own ChaCha stream from the seed.
+    fn faces(&self, nonce: u64, dice: u8) -> Vec<u8> {
+        let mut rng = ChaCha20Rng::from_seed(self.0);
+        rng.set_stream(nonce);
+        (0..dice).map(|_| rng.gen_range(1..=6)).collect()
+    }
+}
+
+/// Only the commitment is shown, so that the seed can't leak into logs.
+impl fmt::Debug for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Seed({})", self.commitment())
+    }
+}
+
+/// Where a roll's dice came from. Rolling again from the seed committed to with the same nonce
+/// gives the same dice.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    /// [`Seed::commitment`] of the seed rolled from.
+    pub commitment: String,
+    /// Number of rolls made from the seed before this one.
+    pub nonce: u64,
+}
+
+/// The seed a campaign rolls from, as saved in `dice.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    seed: Seed,
+    /// Rolls made from the seed so far.
+    nonce: u64,
+    /// Seeds rolled from before, which have been revealed.
+    #[serde(default)]
+    revealed: Vec<Seed>,
+}
+
+/// The seed the next roll in a campaign will come from.
+#[derive(Debug)]
+pub struct Dice {
+    state: Mutex<State>,
+    path: PathBuf,
+}
+
+impl Dice {
+    /// Load a campaign's seed, picking one at random if it hasn't rolled yet.
+    pub fn load(save_dir: &Path) -> Result<Self> {
+        let path = save_dir.join("dice.toml");
+        let state = match persist::load(&path, Document::Dice)? {
+            Some(state) => state,
+            None => State {
+                seed: Seed::random(),
+                nonce: 0,
+                revealed: Vec::new(),
+            },
+        };
+        Ok(Dice {
+            state: Mutex::new(state),
+            path,
+        })
+    }
+
+    /// Start rolling from a new random seed, revealing the old one so that the rolls made from it
+    /// can be checked. Returns the old seed.
+    pub fn rotate(&self) -> Result<Seed> {
+        let mut state = self.state.lock().unwrap();
+        let old = state.seed;
+        state.revealed.push(old);
+        state.seed = Seed::random();
+        state.nonce = 0;
+        persist::save(&self.path, &*state)?;
+        Ok(old)
+    }
+
+    /// The commitment to the seed rolls are made from now.
+    pub fn commitment(&self) -> String {
+        self.state.lock().unwrap().seed.commitment()
+    }
+
+    /// Every seed the campaign has rolled from, including the current one, which is secret.
+    pub fn seeds(&self) -> Vec<Seed> {
+        let state = self.state.lock().unwrap();
+        let mut seeds = state.revealed.clone();
+        seeds.push(state.seed);
+        seeds
+    }
+
+    /// Take the seed and nonce for a new roll. The nonce is saved as used before the roll is made,
+    /// so that no two rolls come from the same one even if the server stops before the roll is
+    /// saved.
+    fn take(&self) -> Result<(Seed, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let nonce = state.nonce;
+        state.nonce += 1;
+        if let Err(e) = persist::save(&self.path, &*state) {
+            state.nonce = nonce;
+            return Err(e);
+        }
+        Ok((state.seed, nonce))
+    }
+}
+
+/// What a roll is for, which decides how it's resolved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RollKind {
+    /// Just dice, as rolled from the sidebar.
+    #[default]
+    Plain,
+    /// Doing something risky, from a position and for an effect agreed with the GM.
+    Action { position: Position, effect: Effect },
+    /// Resisting a consequence, which costs 6 stress minus the highest die.
+    Resistance,
+    /// Letting the dice decide something outside the crew's control.
+    Fortune,
+    /// How the opening of a score goes.
+    Engagement,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    Controlled,
+    Risky,
+    Desperate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    Zero,
+    Limited,
+    Standard,
+    Great,
+    Extreme,
+}
+
+/// How a roll turned out, read from the highest die, or the lowest for a pool of zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// More than one six.
+    Critical,
+    /// A six.
+    Full,
+    /// A four or five.
+    Partial,
+    /// Nothing higher than a three.
+    Failure,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Roll {
+    pub id: RollId,
+    pub roller: Role,
+    /// Number of dice in the pool. A pool of zero rolls two dice and keeps the lowest.
+    pub pool: u8,
+    /// What each die came up as.
+    pub faces: Vec<u8>,
+    /// Unix time in milliseconds.
+    pub time: u64,
+    #[serde(default)]
+    pub kind: RollKind,
+    /// How the roll turned out, for every kind of roll but plain ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Resolution>,
+    /// Stress a resistance roll costs. A critical clears one stress instead, which is -1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stress: Option<i8>,
+    /// Where the dice came from, for rolls made since rolls were rolled from secret seeds.
+    #[serde(flatten)]
+    pub source: Option<Source>,
+}
+
+impl Roll {
+    /// Roll a pool of six-sided dice for a kind of roll.
+    pub fn new(roller: Role, pool: u8, kind: RollKind, dice: &Dice) -> Result<Self> {
+        if pool > MAX_POOL {
+            return Err(BitdError::validation(
+                "pool",
+                &format!("At most {MAX_POOL} dice can be rolled."),
+            ));
+        }
+        let (seed, nonce) = dice.take()?;
+        let mut roll = Self::resolve(roller, pool, kind, seed.faces(nonce, dice_rolled(pool)));
+        roll.source = Some(Source {
+            commitment: seed.commitment(),
+            nonce,
+        });
+        Ok(roll)
+    }
+
+    /// Roll this roll's dice again from the one of `seeds` it committed to, and check that they,
+    /// and what they were resolved as, match. A roll committed to none of them doesn't match.
+    /// Returns `None` for rolls from before rolls were rolled from secret seeds.
+    pub fn verify(&self, seeds: &[Seed]) -> Option<bool> {
+        let source = self.source.as_ref()?;
+        let Some(seed) = seeds
+            .iter()
+            .find(|seed| seed.commitment() == source.commitment)
+        else {
+            return Some(false);
+        };
+        let again = Self::resolve(
+            self.roller.clone(),
+            self.pool,
+            self.kind,
+            seed.faces(source.nonce, dice_rolled(self.pool)),
+        );
+        Some(
+            again.faces == self.faces && again.result == self.result && again.stress == self.stress,
+        )
+    }
+
+    /// Check that this roll could have been made: it has as many six-sided dice as its pool rolls,
+    /// resolved as they should be.
+    pub fn validate(&self) -> Result<()> {
+        let possible = self.pool <= MAX_POOL
+            && self.faces.len() == usize::from(dice_rolled(self.pool))
+            && self.faces.iter().all(|face| (1..=6).contains(face));
+        let again = Self::resolve(
+            self.roller.clone(),
+            self.pool,
+            self.kind,
+            self.faces.clone(),
+        );
+        if !possible || again.result != self.result || again.stress != self.stress {
+            return Err(BitdError::validation(
+                "roll",
+                &format!("Roll {} has impossible dice or results.", self.id),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Work out the result of a roll whose dice have come up as `faces`.
+    fn resolve(roller: Role, pool: u8, kind: RollKind, faces: Vec<u8>) -> Self {
+        // With no dice in the pool, two are rolled and the worst counts.
+        let counted = if pool == 0 {
+            faces.iter().min()
+        } else {
+            faces.iter().max()
+        }
+        .copied()
+        .unwrap_or(1);
+        let sixes = faces.iter().filter(|&&face| face == 6).count();
+        let result = match counted {
+            6 if pool > 0 && sixes > 1 => Resolution::Critical,
+            6 => Resolution::Full,
+            4 | 5 => Resolution::Partial,
+            _ => Resolution::Failure,
+        };
+        let stress = match (kind, result) {
+            (RollKind::Resistance, Resolution::Critical) => Some(-1),
+            (RollKind::Resistance, _) => Some(6 - counted as i8),
+            _ => None,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Roll {
+            id: Uuid::now_v7(),
+            roller,
+            pool,
+            faces,
+            time,
+            kind,
+            result: (kind != RollKind::Plain).then_some(result),
+            stress,
+            source: None,
+        }
+    }
+}
+
+/// Number of dice rolled for a pool.
+fn dice_rolled(pool: u8) -> u8 {
+    if pool == 0 {
+        2
+    } else {
+        pool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::TempDir;
+
+    fn resolve(pool: u8, kind: RollKind, faces: &[u8]) -> Roll {
+        Roll::resolve(Role::Gm, pool, kind, faces.to_vec())
+    }
+
+    const ACTION: RollKind = RollKind::Action {
+        position: Position::Risky,
+        effect: Effect::Standard,
+    };
+
+    #[test]
+    fn action_rolls_read_the_highest_die() {
+        assert_eq!(
+            resolve(3, ACTION, &[1, 6, 2]).result,
+            Some(Resolution::Full)
+        );
+        assert_eq!(
+            resolve(2, ACTION, &[4, 3]).result,
+            Some(Resolution::Partial)
+        );
+        assert_eq!(
+            resolve(2, ACTION, &[3, 1]).result,
+            Some(Resolution::Failure)
+        );
+        assert_eq!(
+            resolve(3, ACTION, &[6, 2, 6]).result,
+            Some(Resolution::Critical)
+        );
+    }
+
+    #[test]
+    fn zero_dice_take_the_lowest_and_never_crit() {
+        assert_eq!(
+            resolve(0, ACTION, &[6, 3]).result,
+            Some(Resolution::Failure)
+        );
+        assert_eq!(resolve(0, ACTION, &[6, 6]).result, Some(Resolution::Full));
+    }
+
+    #[test]
+    fn resistance_costs_six_minus_the_highest_die() {
+        assert_eq!(resolve(2, RollKind::Resistance, &[2, 4]).stress, Some(2));
+        assert_eq!(resolve(1, RollKind::Resistance, &[6]).stress, Some(0));
+        assert_eq!(resolve(2, RollKind::Resistance, &[6, 6]).stress, Some(-1));
+        assert_eq!(resolve(0, RollKind::Resistance, &[5, 1]).stress, Some(5));
+    }
+
+    #[test]
+    fn rolls_from_the_same_seed_and_nonce_match() {
+        let seed = Seed([7; 32]);
+        assert_eq!(seed.faces(3, 10), seed.faces(3, 10));
+        assert_ne!(seed.faces(3, 10), seed.faces(4, 10));
+    }
+
+    #[test]
+    fn seeded_rolls_verify_once_the_seed_is_revealed() {
+        let dir = TempDir::new();
+        let dice = Dice::load(&dir.0).unwrap();
+        let first = Roll::new(Role::Gm, 4, ACTION, &dice).unwrap();
+        let commitment = dice.commitment();
+        assert_eq!(first.source.as_ref().unwrap().nonce, 0);
+        // Nothing about the roll gives the seed away.
+        let json = serde_json::to_string(&first).unwrap();
+        assert!(json.contains(&commitment));
+        assert!(!json.contains(&hex::encode(dice.seeds()[0].0)));
+
+        // The nonce carries on from where it was when the campaign is loaded again.
+        let dice = Dice::load(&dir.0).unwrap();
+        let second = Roll::new(Role::Gm, 4, ACTION, &dice).unwrap();
+        assert_eq!(
+            second.source,
+            Some(Source {
+                commitment: commitment.clone(),
+                nonce: 1
+            })
+        );
+
+        let revealed = dice.rotate().unwrap();
+        assert_eq!(revealed.commitment(), commitment);
+        assert_ne!(dice.commitment(), commitment);
+        assert_eq!(first.verify(&[revealed]), Some(true));
+        assert_eq!(second.verify(&dice.seeds()), Some(true));
+        assert_eq!(second.verify(&[Seed::random()]), Some(false));
+
+        let mut fudged = second;
+        fudged.faces[0] = fudged.faces[0] % 6 + 1;
+        assert_eq!(fudged.verify(&dice.seeds()), Some(false));
+    }
+
+    #[test]
+    fn plain_rolls_have_no_result() {
+        let roll = resolve(2, RollKind::Plain, &[6, 6]);
+        assert_eq!(roll.result, None);
+        assert_eq!(roll.stress, None);
+    }
+}
@@ -0,0 +1,309 @@
+//! An append-only log of every change made to a campaign: who made it, when, what they asked for
+//! and what it changed.
+//!
+//! Events are appended to `log/current.jsonl` in the campaign's directory before the change is
+//! saved to storage, so a change that was logged but never saved is redone when the campaign is
+//! next loaded. Once storage has caught up with the log, compaction moves the current log aside
+//! into a segment named after the revisions it covers, e.g.
+//! `log/1692345678901-1692345679900.jsonl`, and starts a new one. Segments are never read again
+//! by the server, but are kept as history.
+//!
+//! Each event records the [`schema`] version it was written in. Events aren't upgraded, but one
+//! newer than this server understands stops the campaign from loading.
+
+use crate::{access::Role, persist, schema, BitdError, Instruction, Result, SyncRequest};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const CURRENT_LOG: &str = "current.jsonl";
+
+/// Who made a change, and what they asked for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cause {
+    /// Identifies the websocket connection the change came through, if it came through one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<Uuid>,
+    pub role: Role,
+    /// The instruction a client sent, or none for changes made by the server itself, like
+    /// imports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instruction: Option<Instruction>,
+}
+
+impl Cause {
+    /// A change made by the server on the GM's behalf.
+    pub fn server() -> Self {
+        Cause {
+            connection: None,
+            role: Role::Gm,
+            instruction: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    #[serde(default)]
+    pub version: u32,
+    /// Revision of state the change brought the campaign to.
+    pub rev: u64,
+    /// Unix time in milliseconds.
+    pub time: u64,
+    #[serde(flatten)]
+    pub cause: Cause,
+    pub change: SyncRequest,
+}
+
+impl Event {
+    pub fn new(rev: u64, cause: Cause, change: SyncRequest) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Event {
+            version: schema::VERSION,
+            rev,
+            time,
+            cause,
+            change,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventLog {
+    dir: PathBuf,
+    file: File,
+    /// Revisions of the first and last events in the current log, if it has any.
+    first: Option<u64>,
+    last: Option<u64>,
+    len: usize,
+    /// Number of events after which the log is compacted.
+    compact_after: usize,
+    /// Set when a logged change failed to save, so that compacting would lose it.
+    storage_behind: bool,
+}
+
+impl EventLog {
+    /// Open the log in `save_dir`, returning it along with the events in the current log, which
+    /// may not have been saved to storage yet.
+    pub fn open(save_dir: &Path, compact_after: usize) -> Result<(Self, Vec<Event>)> {
+        let dir = save_dir.join("log");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(CURRENT_LOG);
+        let (events, complete) = read(&path)?;
+        if !complete {
+            // Drop the incomplete line so that new events don't get appended to it.
+            let mut contents = Vec::new();
+            for event in &events {
+                contents.extend(to_line(event)?);
+            }
+            persist::write_atomic(&path, &contents)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let log = EventLog {
+            dir,
+            file,
+            first: events.first().map(|e| e.rev),
+            last: events.last().map(|e| e.rev),
+            len: events.len(),
+            compact_after,
+            storage_behind: false,
+        };
+        Ok((log, events))
+    }
+
+    /// Append an event, making sure it's on disk before returning.
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        self.file.write_all(&to_line(event)?)?;
+        self.file.sync_data()?;
+        self.first.get_or_insert(event.rev);
+        self.last = Some(event.rev);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Note that a logged change could not be saved to storage, and must be kept in the current
+    /// log to be replayed.
+    pub fn storage_behind(&mut self) {
+        self.storage_behind = true;
+    }
+
+    /// Note that everything logged so far has been saved to storage, e.g. by saving all of the
+    /// state at once, so the log can be compacted again.
+    pub fn storage_caught_up(&mut self) {
+        self.storage_behind = false;
+    }
+
+    /// The logged events that storage may not have caught up with: those in the current log, if
+    /// any change failed to save since it was started.
+    pub fn unsaved(&self) -> Result<Vec<Event>> {
+        if !self.storage_behind {
+            return Ok(Vec::new());
+        }
+        Ok(read(&self.dir.join(CURRENT_LOG))?.0)
+    }
+
+    /// Compact the log if it has grown past its limit.
+    pub fn maybe_compact(&mut self) -> Result<()> {
+        if self.len >= self.compact_after {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Move the current log into a segment and start a new one. Only safe once every logged
+    /// change has been saved to storage.
+    pub fn compact(&mut self) -> Result<()> {
+        let (Some(first), Some(last)) = (self.first, self.last) else {
+            return Ok(());
+        };
+        if self.storage_behind {
+            return Ok(());
+        }
+        let current = self.dir.join(CURRENT_LOG);
+        fs::rename(&current, self.dir.join(format!("{first}-{last}.jsonl")))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&current)?;
+        self.first = None;
+        self.last = None;
+        self.len = 0;
+        tracing::debug!("compacted {}", current.display());
+        Ok(())
+    }
+}
+
+fn to_line(event: &Event) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(event).map_err(|e| BitdError::Persistence {
+        message: e.to_string(),
+    })?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Read the events in a log file, and whether it was complete. A crash while appending can
+/// leave the last line incomplete, which is ignored; anything else that can't be read means the
+/// log is corrupt.
+fn read(path: &Path) -> Result<(Vec<Event>, bool)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), true)),
+        Err(e) => return Err(e.into()),
+    };
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut events = Vec::with_capacity(lines.len());
+    let mut complete = true;
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str::<Event>(line) {
+            Ok(event) => {
+                schema::check(event.version).map_err(|message| BitdError::Migration {
+                    path: path.display().to_string(),
+                    message: format!("line {}: {message}", i + 1),
+                })?;
+                events.push(event)
+            }
+            Err(_) if i == lines.len() - 1 => {
+                complete = false;
+                tracing::warn!(
+                    "ignoring an incomplete event at the end of {}",
+                    path.display()
+                );
+            }
+            Err(e) => {
+                return Err(BitdError::CorruptFile {
+                    path: path.display().to_string(),
+                    message: format!("line {}: {e}", i + 1),
+                })
+            }
+        }
+    }
+    Ok((events, complete))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::TempDir;
+
+    fn event(rev: u64) -> Event {
+        Event::new(rev, Cause::server(), SyncRequest::Purge(Vec::new()))
+    }
+
+    #[test]
+    fn reopened_log_has_its_events() {
+        let dir = TempDir::new();
+        let (mut log, events) = EventLog::open(&dir.0, 100).unwrap();
+        assert!(events.is_empty());
+        log.append(&event(1)).unwrap();
+        log.append(&event(2)).unwrap();
+        drop(log);
+
+        let (mut log, events) = EventLog::open(&dir.0, 100).unwrap();
+        assert_eq!(events.iter().map(|e| e.rev).collect::<Vec<_>>(), [1, 2]);
+        log.append(&event(3)).unwrap();
+        drop(log);
+
+        let (_, events) = EventLog::open(&dir.0, 100).unwrap();
+        assert_eq!(events.iter().map(|e| e.rev).collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn incomplete_last_event_is_dropped() {
+        let dir = TempDir::new();
+        let (mut log, _) = EventLog::open(&dir.0, 100).unwrap();
+        log.append(&event(1)).unwrap();
+        drop(log);
+        // As left by a crash partway through appending.
+        let path = dir.0.join("log").join(CURRENT_LOG);
+        let line = to_line(&event(2)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&line[..line.len() / 2]).unwrap();
+        drop(file);
+
+        let (mut log, events) = EventLog::open(&dir.0, 100).unwrap();
+        assert_eq!(events.iter().map(|e| e.rev).collect::<Vec<_>>(), [1]);
+        // New events start on a line of their own.
+        log.append(&event(3)).unwrap();
+        drop(log);
+        let (_, events) = EventLog::open(&dir.0, 100).unwrap();
+        assert_eq!(events.iter().map(|e| e.rev).collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    fn log_is_only_compacted_once_storage_has_caught_up() {
+        let dir = TempDir::new();
+        let (mut log, _) = EventLog::open(&dir.0, 1).unwrap();
+        log.append(&event(1)).unwrap();
+        log.storage_behind();
+        log.maybe_compact().unwrap();
+        assert_eq!(log.unsaved().unwrap().len(), 1);
+
+        log.storage_caught_up();
+        log.maybe_compact().unwrap();
+        let (_, events) = EventLog::open(&dir.0, 1).unwrap();
+        assert!(events.is_empty());
+        assert!(dir.0.join("log").join("1-1.jsonl").exists());
+    }
+
+    #[test]
+    fn corrupt_event_before_the_end_is_an_error() {
+        let dir = TempDir::new();
+        let (mut log, _) = EventLog::open(&dir.0, 100).unwrap();
+        log.append(&event(1)).unwrap();
+        drop(log);
+        let path = dir.0.join("log").join(CURRENT_LOG);
+        let mut contents = b"{not an event}\n".to_vec();
+        contents.extend(fs::read(&path).unwrap());
+        fs::write(&path, contents).unwrap();
+
+        let opened = EventLog::open(&dir.0, 100);
+        assert!(matches!(opened, Err(BitdError::CorruptFile { .. })));
+    }
+}
@@ -0,0 +1,277 @@
+//! A whole campaign as a single JSON document, for backups and for moving campaigns between
+//! servers. Exports are versioned so that older ones can still be imported as the server grows.
+
+use crate::access::now;
+use crate::campaign::Campaigns;
+use crate::config::Command;
+use crate::dice::{Roll, RollId};
+use crate::trash::{TrashItem, Trashed};
+use crate::{
+    persist, validate_name, Bitd, BitdError, Clock, ClockId, Landmark, LandmarkId, Note, NoteId,
+    PlayerData, PlayerId, Result, MAX_CLOCK_SLICES,
+};
+use anyhow::Context;
+use clap::ValueEnum;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use uuid::Uuid;
+
+/// Identifies a JSON document as a campaign export.
+pub const FORMAT: &str = "bitd-campaign";
+/// Version of the export format written by this server. Exports with a higher version can't be
+/// imported.
+pub const VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Export {
+    pub format: String,
+    pub version: u32,
+    /// Name of the campaign that was exported. Imports may go into a campaign of any name.
+    #[serde(default)]
+    pub campaign: String,
+    /// Unix time it was exported.
+    #[serde(default)]
+    pub exported: u64,
+    #[serde(default)]
+    pub players: DashMap<PlayerId, PlayerData>,
+    #[serde(default)]
+    pub landmarks: DashMap<LandmarkId, Landmark>,
+    #[serde(default)]
+    pub notes: DashMap<NoteId, Note>,
+    #[serde(default)]
+    pub trash: DashMap<Uuid, TrashItem>,
+    #[serde(default)]
+    pub rolls: DashMap<RollId, Roll>,
+}
+
+/// What to do with a campaign's existing state when importing into it.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Keep everything, replacing only what has the same id as something imported.
+    #[default]
+    Merge,
+    /// Throw everything away first.
+    Replace,
+}
+
+impl Export {
+    /// Export the current state of a campaign.
+    pub fn new(campaign: &str, bitd: &Bitd) -> Self {
+        Export {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            campaign: campaign.to_string(),
+            exported: now(),
+            players: (*bitd.players).clone(),
+            landmarks: (*bitd.landmarks).clone(),
+            notes: (*bitd.notes).clone(),
+            trash: (*bitd.trash).clone(),
+            rolls: (*bitd.rolls).clone(),
+        }
+    }
+
+    /// Check that this is an export this server can read, and that everything in it is valid.
+    pub fn validate(&self) -> Result<()> {
+        if self.format != FORMAT {
+            return Err(BitdError::validation(
+                "format",
+                "This is not a campaign export.",
+            ));
+        }
+        if self.version > VERSION {
+            return Err(BitdError::validation(
+                "version",
+                &format!(
+                    "This export is version {}, but this server can only import up to version {VERSION}.",
+                    self.version
+                ),
+            ));
+        }
+        for player in self.players.iter() {
+            validate_player(&player)?;
+        }
+        for landmark in self.landmarks.iter() {
+            validate_landmark(&landmark)?;
+        }
+        // What's in the trash must be valid too, and not also be out of it, which is all there is
+        // to check about notes.
+        for item in self.trash.iter() {
+            if item.id != *item.key() {
+                return Err(BitdError::validation(
+                    "trash",
+                    &format!("Trash item {} is filed under {}.", item.id, item.key()),
+                ));
+            }
+            let live = match &item.entity {
+                Trashed::Player { player } => {
+                    validate_player(player)?;
+                    self.players.contains_key(&item.id)
+                }
+                Trashed::Clock { player_id, clock } => {
+                    validate_clock(item.id, clock)?;
+                    self.players
+                        .get(player_id)
+                        .is_some_and(|player| player.clocks.contains_key(&item.id))
+                }
+                Trashed::Landmark { landmark } => {
+                    validate_landmark(landmark)?;
+                    self.landmarks.contains_key(&item.id)
+                }
+                Trashed::Note { .. } => self.notes.contains_key(&item.id),
+            };
+            if live {
+                return Err(BitdError::validation(
+                    "trash",
+                    &format!("{} is both in the trash and out of it.", item.key()),
+                ));
+            }
+        }
+        for roll in self.rolls.iter() {
+            if roll.id != *roll.key() {
+                return Err(BitdError::validation(
+                    "roll",
+                    &format!("Roll {} is filed under {}.", roll.id, roll.key()),
+                ));
+            }
+            roll.validate()?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_player(player: &PlayerData) -> Result<()> {
+    validate_name("name", &player.name)?;
+    player.sheet.validate()?;
+    for clock in player.clocks.iter() {
+        validate_clock(*clock.key(), &clock)?;
+    }
+    Ok(())
+}
+
+fn validate_clock(id: ClockId, clock: &Clock) -> Result<()> {
+    validate_name("task", &clock.task)?;
+    if !(1..=MAX_CLOCK_SLICES).contains(&clock.slices) || clock.progress > clock.slices {
+        return Err(BitdError::validation(
+            "slices",
+            &format!("Clock {id} has an impossible size or progress."),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_landmark(landmark: &Landmark) -> Result<()> {
+    validate_name("name", &landmark.name)?;
+    if !landmark.x.is_finite() || !landmark.y.is_finite() {
+        return Err(BitdError::validation(
+            "position",
+            "Landmark coordinates must be finite numbers.",
+        ));
+    }
+    Ok(())
+}
+
+/// Carry out a command given on the command line instead of serving.
+pub fn run(command: Command, campaigns: &Campaigns) -> anyhow::Result<()> {
+    match command {
+        Command::Export { campaign, file } => {
+            let export = campaigns.export(&campaign)?;
+            let json = serde_json::to_vec_pretty(&export)?;
+            persist::write_atomic(&file, &json)?;
+            println!("Exported {campaign} to {}", file.display());
+        }
+        Command::Import {
+            campaign,
+            file,
+            mode,
+        } => {
+            let json =
+                fs::read(&file).with_context(|| format!("Could not read {}", file.display()))?;
+            let export = serde_json::from_slice(&json)
+                .with_context(|| format!("{} is not a campaign export", file.display()))?;
+            campaigns.import(&campaign, export, mode)?;
+            println!("Imported {} into {campaign}", file.display());
+        }
+        Command::VerifyRolls { campaign } => {
+            let mut rolls: Vec<Roll> = campaigns
+                .export(&campaign)?
+                .rolls
+                .into_iter()
+                .map(|(_, roll)| roll)
+                .collect();
+            rolls.sort_by_key(|roll| roll.id);
+            let seeds = campaigns.seeds(&campaign)?;
+            let mut forged = 0;
+            for roll in &rolls {
+                let Some(source) = &roll.source else {
+                    println!("{} {:?}: not seeded", roll.id, roll.faces);
+                    continue;
+                };
+                let verdict = if roll.verify(&seeds) == Some(true) {
+                    "ok"
+                } else {
+                    forged += 1;
+                    "DOES NOT MATCH"
+                };
+                println!(
+                    "{} {:?} from seed {} nonce {}: {verdict}",
+                    roll.id, roll.faces, source.commitment, source.nonce
+                );
+            }
+            anyhow::ensure!(
+                forged == 0,
+                "{forged} of {} rolls don't match their seed",
+                rolls.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::RollKind;
+    use crate::storage::tests::sample;
+
+    fn export() -> Export {
+        let saved = sample();
+        Export {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            campaign: "test".to_string(),
+            exported: now(),
+            players: saved.players,
+            landmarks: saved.landmarks,
+            notes: saved.notes,
+            trash: saved.trash,
+            rolls: saved.rolls,
+        }
+    }
+
+    #[test]
+    fn everything_in_an_export_is_validated() {
+        export().validate().unwrap();
+
+        let trashed = export();
+        let id = *trashed.trash.iter().next().unwrap().key();
+        let note = trashed.notes.iter().next().unwrap().clone();
+        trashed.notes.insert(id, note);
+        assert!(trashed.validate().is_err());
+
+        let fudged = export();
+        fudged
+            .rolls
+            .iter_mut()
+            .for_each(|mut roll| roll.faces = vec![6, 7]);
+        assert!(fudged.validate().is_err());
+
+        let resolved = export();
+        resolved.rolls.iter_mut().for_each(|mut roll| {
+            roll.kind = RollKind::Resistance;
+            roll.stress = Some(0);
+        });
+        assert!(resolved.validate().is_err());
+    }
+}
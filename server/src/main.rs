@@ -1,39 +1,176 @@
-use anyhow::Result;
+use access::{Access, Invite, InviteId, Role};
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        FromRequestParts, Path, Query, State,
     },
-    response::IntoResponse,
-    routing::get,
-    Router,
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
 use axum_extra::routing::SpaRouter;
-use glob::glob;
-use std::path::PathBuf;
+use campaign::{Applied, Campaign, Campaigns, Stamped, DEFAULT_CAMPAIGN};
+use config::Config;
+use dice::{Dice, Roll, RollId, RollKind, Seed};
+use events::Cause;
+use export::{Export, ImportMode};
+use sheet::{Sheet, SheetField, StressReason, Transition, PUSH_STRESS};
+use storage::Storage;
 use thiserror::Error;
+use trash::{TrashItem, Trashed};
 // use axum_typed_websockets::{Message, WebSocket, WebSocketUpgrade}
 use dashmap::DashMap;
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::broadcast;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+};
 use uuid::Uuid;
 
+mod access;
+mod campaign;
+mod config;
+mod dice;
+mod events;
+mod export;
+mod persist;
+mod schema;
+mod sheet;
+mod snapshot;
+mod storage;
+mod tls;
+mod trash;
+
 type ClockId = Uuid;
 type PlayerId = Uuid;
 type LandmarkId = Uuid;
 type NoteId = Uuid;
 
-#[derive(Clone, Debug, Error, Serialize)]
+/// Largest number of segments a clock may have.
+const MAX_CLOCK_SLICES: u8 = 50;
+
+/// Errors reported to clients. Serialized with a stable `code` field identifying the kind of
+/// error, alongside any ids or details relevant to it.
+#[derive(Clone, Debug, Error, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
 pub enum BitdError {
-    #[error("Player lookup failed.\nPlayer: {0}")]
-    PlayerLookup(PlayerId),
-    #[error("Clock lookup failed.\nPlayer: {0}\nClock: {1}")]
-    ClockLookup(PlayerId, ClockId),
+    #[error("Player lookup failed.\nPlayer: {player_id}")]
+    PlayerLookup { player_id: PlayerId },
+    #[error("Clock lookup failed.\nPlayer: {player_id}\nClock: {clock_id}")]
+    ClockLookup {
+        player_id: PlayerId,
+        clock_id: ClockId,
+    },
+    #[error("Landmark lookup failed.\nLandmark: {landmark_id}")]
+    LandmarkLookup { landmark_id: LandmarkId },
+    #[error("Note lookup failed.\nNote: {note_id}")]
+    NoteLookup { note_id: NoteId },
+    #[error("Failed to save or load state.\nCause: {message}")]
+    Persistence { message: String },
+    #[error(
+        "A save file is corrupt, so the campaign was not loaded.\nFile: {path}\nCause: {message}"
+    )]
+    CorruptFile { path: String, message: String },
+    #[error(
+        "A save file could not be upgraded to the current format, so the campaign was not loaded.\nFile: {path}\nCause: {message}"
+    )]
+    Migration { path: String, message: String },
+    #[error("Could not parse an instruction.\nCause: {message}")]
+    InvalidInstruction { message: String },
+    #[error("Invalid {field}.\nCause: {message}")]
+    Validation { field: String, message: String },
+    #[error("Campaign lookup failed.\nCampaign: {campaign}")]
+    CampaignLookup { campaign: String },
+    #[error("Campaign already exists.\nCampaign: {campaign}")]
+    CampaignExists { campaign: String },
+    #[error("Campaign has connected clients.\nCampaign: {campaign}")]
+    CampaignInUse { campaign: String },
+    #[error("Not allowed.\nCause: {message}")]
+    Forbidden { message: String },
+    #[error("Invalid join token.")]
+    InvalidToken,
+    #[error("Invite lookup failed.\nInvite: {invite_id}")]
+    InviteLookup { invite_id: InviteId },
+    #[error("Nothing with this id is in the trash.\nId: {id}")]
+    TrashLookup { id: Uuid },
+    #[error("Snapshot lookup failed.\nSnapshot: {snapshot}")]
+    SnapshotLookup { snapshot: String },
+}
+
+impl BitdError {
+    fn validation(field: &str, message: &str) -> Self {
+        BitdError::Validation {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for BitdError {
+    fn from(e: std::io::Error) -> Self {
+        BitdError::Persistence {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<toml::ser::Error> for BitdError {
+    fn from(e: toml::ser::Error) -> Self {
+        BitdError::Persistence {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<toml::de::Error> for BitdError {
+    fn from(e: toml::de::Error) -> Self {
+        BitdError::Persistence {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for BitdError {
+    fn from(e: rusqlite::Error) -> Self {
+        BitdError::Persistence {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for BitdError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            BitdError::PlayerLookup { .. }
+            | BitdError::ClockLookup { .. }
+            | BitdError::LandmarkLookup { .. }
+            | BitdError::NoteLookup { .. }
+            | BitdError::InviteLookup { .. }
+            | BitdError::TrashLookup { .. }
+            | BitdError::SnapshotLookup { .. }
+            | BitdError::CampaignLookup { .. } => StatusCode::NOT_FOUND,
+            BitdError::CampaignExists { .. } | BitdError::CampaignInUse { .. } => {
+                StatusCode::CONFLICT
+            }
+            BitdError::InvalidInstruction { .. } | BitdError::Validation { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            BitdError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            BitdError::InvalidToken => StatusCode::UNAUTHORIZED,
+            BitdError::Persistence { .. }
+            | BitdError::CorruptFile { .. }
+            | BitdError::Migration { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(self)).into_response()
+    }
 }
 
+type Result<T, E = BitdError> = std::result::Result<T, E>;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Clock {
     task: String,
@@ -63,6 +200,8 @@ impl Clock {
 struct PlayerData {
     name: String,
     clocks: DashMap<ClockId, Clock>,
+    #[serde(default)]
+    sheet: Box<Sheet>,
 }
 
 impl PlayerData {
@@ -70,17 +209,19 @@ impl PlayerData {
         PlayerData {
             name,
             clocks: DashMap::new(),
+            sheet: Box::default(),
         }
     }
 
-    fn add_clock(&mut self, task: String, slices: u8) -> ClockId {
+    fn add_clock(&mut self, task: String, slices: u8) -> (ClockId, Clock) {
         let id = Uuid::now_v7();
-        self.clocks.insert(id, Clock::new(task, slices));
-        id
+        let clock = Clock::new(task, slices);
+        self.clocks.insert(id, clock.clone());
+        (id, clock)
     }
 
-    fn delete_clock(&mut self, id: ClockId) {
-        self.clocks.remove(&id);
+    fn delete_clock(&mut self, id: ClockId) -> Option<Clock> {
+        self.clocks.remove(&id).map(|(_, clock)| clock)
     }
 
     fn rename(&mut self, name: String) -> String {
@@ -113,254 +254,1165 @@ struct Note {
     cat: NoteCategory,
 }
 
+/// Check that a user-supplied name isn't blank.
+fn validate_name(field: &str, name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(BitdError::validation(field, "Cannot be blank."));
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 struct Bitd {
     players: Arc<DashMap<PlayerId, PlayerData>>,
     landmarks: Arc<DashMap<LandmarkId, Landmark>>,
     notes: Arc<DashMap<NoteId, Note>>,
-    save_dir: PathBuf,
+    trash: Arc<DashMap<Uuid, TrashItem>>,
+    rolls: Arc<DashMap<RollId, Roll>>,
+    dice: Arc<Dice>,
+    storage: Arc<dyn Storage>,
+    // Days deleted things are kept in the trash for, or 0 to keep them forever.
+    trash_retention_days: u64,
+    // Number of recent rolls kept.
+    roll_history: usize,
 }
 
 impl Bitd {
-    fn new(save_dir: PathBuf) -> Self {
-        let mut bitd = Bitd {
-            players: Arc::new(DashMap::new()),
-            landmarks: Arc::new(DashMap::new()),
-            notes: Arc::new(DashMap::new()),
-            save_dir,
-        };
-
-        match fs::create_dir_all(&bitd.save_dir) {
-            Err(e) => {
-                println!(
-                    "Could not create save directory at {}. Cause:\n {}",
-                    &bitd.save_dir.display(),
-                    e
-                )
-            }
-            Ok(_) => {
-                // if the previous create_dir succeeded, should be fine to unwrap
-                fs::create_dir_all(bitd.players_dir()).unwrap();
-            }
-        }
-
-        if let Err(e) = bitd.load_players_backup() {
-            println!("Warning: Failed to load players from backup. Cause:\n {e}");
-        }
-
-        if let Err(_) = bitd.load_landmarks_backup() {
-            println!(
-                "Did not find a landmarks backup. One will be created at ./data/landmarks.toml"
-            );
-        }
-
-        if let Err(_) = bitd.load_notes_backup() {
-            println!("Did not find a notes backup. One will be created at ./data/notes.toml");
-        }
-
-        bitd
+    /// Load the state kept in `storage`, which will be kept up to date as state changes.
+    fn new(
+        storage: Arc<dyn Storage>,
+        dice: Dice,
+        trash_retention_days: u64,
+        roll_history: usize,
+    ) -> Result<Self> {
+        let saved = storage.load()?;
+        Ok(Bitd {
+            players: Arc::new(saved.players),
+            landmarks: Arc::new(saved.landmarks),
+            notes: Arc::new(saved.notes),
+            trash: Arc::new(saved.trash),
+            rolls: Arc::new(saved.rolls),
+            dice: Arc::new(dice),
+            storage,
+            trash_retention_days,
+            roll_history,
+        })
     }
 
-    fn add_player(&mut self, name: String) -> PlayerId {
+    fn add_player(&self, name: String) -> Result<(PlayerId, PlayerData)> {
+        validate_name("name", &name)?;
         let player_id = Uuid::now_v7();
-        self.players.insert(player_id, PlayerData::new(name));
-        player_id
+        let player = PlayerData::new(name);
+        self.players.insert(player_id, player.clone());
+        Ok((player_id, player))
     }
 
-    fn rename_player(&mut self, player_id: PlayerId, name: String) -> Option<String> {
-        self.players.get_mut(&player_id).map(|mut p| p.rename(name))
+    fn rename_player(&self, player_id: PlayerId, name: String) -> Result<String> {
+        validate_name("name", &name)?;
+        self.players
+            .get_mut(&player_id)
+            .map(|mut p| p.rename(name))
+            .ok_or(BitdError::PlayerLookup { player_id })
     }
 
-    fn remove_player(&mut self, player_id: PlayerId) {
-        // TODO: error handling
-        self.players.remove(&player_id);
-        if fs::remove_file(format!("./players/{}.toml", player_id)).is_err() {
-            println!("Failed to remove user file: {}", player_id)
-        };
+    /// Change a field of a player's sheet, returning its old value.
+    fn edit_sheet(&self, player_id: PlayerId, field: SheetField) -> Result<SheetField> {
+        self.players
+            .get_mut(&player_id)
+            .ok_or(BitdError::PlayerLookup { player_id })?
+            .sheet
+            .set(field)
     }
 
-    fn add_clock(&self, player_id: PlayerId, task: String, slices: u8) -> Result<ClockId> {
+    fn remove_player(&self, player_id: PlayerId) -> Result<PlayerData> {
+        self.players
+            .remove(&player_id)
+            .map(|(_, player)| player)
+            .ok_or(BitdError::PlayerLookup { player_id })
+    }
+
+    fn add_clock(&self, player_id: PlayerId, task: String, slices: u8) -> Result<(ClockId, Clock)> {
+        validate_name("task", &task)?;
+        if !(1..=MAX_CLOCK_SLICES).contains(&slices) {
+            return Err(BitdError::validation(
+                "slices",
+                &format!("Clocks must have between 1 and {MAX_CLOCK_SLICES} segments."),
+            ));
+        }
         Ok(self
             .players
             .get_mut(&player_id)
-            .ok_or(BitdError::PlayerLookup(player_id))?
+            .ok_or(BitdError::PlayerLookup { player_id })?
             .add_clock(task, slices))
     }
 
-    fn delete_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<()> {
+    fn clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<Clock> {
+        self.players
+            .get(&player_id)
+            .ok_or(BitdError::PlayerLookup { player_id })?
+            .clocks
+            .get(&clock_id)
+            .map(|clock| clock.clone())
+            .ok_or(BitdError::ClockLookup {
+                player_id,
+                clock_id,
+            })
+    }
+
+    fn delete_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<Clock> {
         self.players
             .get_mut(&player_id)
-            .ok_or(BitdError::PlayerLookup(player_id))?
-            .delete_clock(clock_id);
-        Ok(())
+            .ok_or(BitdError::PlayerLookup { player_id })?
+            .delete_clock(clock_id)
+            .ok_or(BitdError::ClockLookup {
+                player_id,
+                clock_id,
+            })
     }
 
-    fn increment_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<()> {
+    fn increment_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<Clock> {
         let player = self
             .players
             .get_mut(&player_id)
-            .ok_or(BitdError::PlayerLookup(player_id))?;
-        player
+            .ok_or(BitdError::PlayerLookup { player_id })?;
+        let mut clock = player
             .clocks
             .get_mut(&clock_id)
-            .ok_or(BitdError::ClockLookup(player_id, clock_id))?
-            .increment();
-        Ok(())
+            .ok_or(BitdError::ClockLookup {
+                player_id,
+                clock_id,
+            })?;
+        clock.increment();
+        Ok(clock.clone())
     }
 
-    fn decrement_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<()> {
+    fn decrement_clock(&self, player_id: PlayerId, clock_id: ClockId) -> Result<Clock> {
         let player = self
             .players
             .get_mut(&player_id)
-            .ok_or(BitdError::PlayerLookup(player_id))?;
-        player
+            .ok_or(BitdError::PlayerLookup { player_id })?;
+        let mut clock = player
             .clocks
             .get_mut(&clock_id)
-            .ok_or(BitdError::ClockLookup(player_id, clock_id))?
-            .decrement();
-        Ok(())
+            .ok_or(BitdError::ClockLookup {
+                player_id,
+                clock_id,
+            })?;
+        clock.decrement();
+        Ok(clock.clone())
     }
 
-    fn players_dir(&self) -> String {
-        format!("{}/players", self.save_dir.display())
+    fn add_landmark(&self, name: String, x: f64, y: f64) -> Result<(LandmarkId, Landmark)> {
+        validate_name("name", &name)?;
+        if !x.is_finite() || !y.is_finite() {
+            return Err(BitdError::validation(
+                "position",
+                "Landmark coordinates must be finite numbers.",
+            ));
+        }
+        let id = Uuid::now_v7();
+        let landmark = Landmark { name, x, y };
+        self.landmarks.insert(id, landmark.clone());
+        Ok((id, landmark))
     }
 
-    fn notes_dir(&self) -> String {
-        self.save_dir.display().to_string()
+    fn remove_landmark(&self, id: LandmarkId) -> Result<Landmark> {
+        self.landmarks
+            .remove(&id)
+            .map(|(_, landmark)| landmark)
+            .ok_or(BitdError::LandmarkLookup { landmark_id: id })
     }
 
-    fn landmarks_dir(&self) -> String {
-        self.save_dir.display().to_string()
+    fn add_note(&self, title: String, desc: String, cat: NoteCategory) -> (NoteId, Note) {
+        let id = Uuid::now_v7();
+        let note = Note { title, desc, cat };
+        self.notes.insert(id, note.clone());
+        (id, note)
     }
 
-    fn backup_player(&self, player_id: PlayerId) -> Result<()> {
-        let player = self
-            .players
-            .get(&player_id)
-            .ok_or(BitdError::PlayerLookup(player_id))?;
-        fs::write(
-            format!("{}/{}.toml", self.players_dir(), player_id),
-            toml::to_string_pretty(&*player)?,
-        )?;
-        Ok(())
+    /// Replace a note, returning what it said before.
+    fn edit_note(&self, id: NoteId, note: Note) -> Result<Note> {
+        let mut existing = self
+            .notes
+            .get_mut(&id)
+            .ok_or(BitdError::NoteLookup { note_id: id })?;
+        Ok(std::mem::replace(&mut *existing, note))
     }
 
-    fn load_players_backup(&mut self) -> Result<()> {
-        for path in glob(&format!("{}/*.toml", self.players_dir()))
-            .expect("Failed to read glob pattern.")
-            .filter_map(Result::ok)
-        {
-            if let (Some(stem), Ok(contents)) = (
-                path.file_stem().unwrap().to_str(), // unwrap is safe bc we know path matches *.toml
-                &fs::read_to_string(&path),
-            ) {
-                if let Ok(uuid) = Uuid::try_parse(stem) {
-                    if let Ok(player) = toml::from_str(contents) {
-                        self.players.insert(uuid, player);
-                    }
-                }
+    fn remove_note(&self, id: NoteId) -> Result<Note> {
+        self.notes
+            .remove(&id)
+            .map(|(_, note)| note)
+            .ok_or(BitdError::NoteLookup { note_id: id })
+    }
+
+    /// Add a roll to the history, forgetting the oldest rolls if it's full.
+    fn record_roll(&self, roll: Roll) {
+        self.rolls.insert(roll.id, roll);
+        let excess = self.rolls.len().saturating_sub(self.roll_history);
+        if excess > 0 {
+            // Roll ids are v7 uuids, which sort in the order they were made.
+            let mut ids: Vec<RollId> = self.rolls.iter().map(|roll| *roll.key()).collect();
+            ids.sort();
+            for id in &ids[..excess] {
+                self.rolls.remove(id);
             }
         }
-        Ok(())
     }
 
-    fn add_landmark(&mut self, name: String, x: f64, y: f64) -> LandmarkId {
-        let id = Uuid::now_v7();
-        self.landmarks.insert(id, Landmark { name, x, y });
-        id
+    /// Roll dice for someone and keep the roll in the history. Resistance rolls for a player,
+    /// which players' own rolls always are, cost that player the stress they roll.
+    fn roll(
+        &self,
+        role: &Role,
+        pool: u8,
+        kind: RollKind,
+        player_id: Option<PlayerId>,
+    ) -> Result<Outcome> {
+        let resisting = match (kind, player_id, role) {
+            (RollKind::Resistance, Some(player_id), _) => Some(player_id),
+            (RollKind::Resistance, None, Role::Player { player_id }) => Some(*player_id),
+            _ => None,
+        };
+        // Check before rolling, so that there's no roll if its stress can't be taken.
+        if let Some(player_id) = resisting {
+            self.players
+                .get(&player_id)
+                .ok_or(BitdError::PlayerLookup { player_id })?
+                .sheet
+                .check_active()?;
+        }
+        let roll = Roll::new(role.clone(), pool, kind, &self.dice)?;
+        self.record_roll(roll.clone());
+        let created = Some(roll.id);
+        let mut changes = vec![SyncRequest::Roll(roll.clone())];
+        if let (Some(player_id), Some(stress)) = (resisting, roll.stress) {
+            changes.extend(self.stress_rules(player_id, |sheet| {
+                sheet.take_stress(stress, StressReason::Resistance)
+            })?);
+        }
+        // Rolls, and the stress they cost, can't be taken back.
+        Ok(Outcome::Changes { changes, created })
     }
 
-    fn remove_landmark(&mut self, id: LandmarkId) {
-        self.landmarks.remove(&id);
+    /// Follow the stress and trauma rules on a player's sheet, returning each step as a change.
+    fn stress_rules(
+        &self,
+        player_id: PlayerId,
+        rule: impl FnOnce(&mut Sheet) -> Result<Vec<Transition>>,
+    ) -> Result<Vec<SyncRequest>> {
+        let mut player = self
+            .players
+            .get_mut(&player_id)
+            .ok_or(BitdError::PlayerLookup { player_id })?;
+        Ok(rule(&mut player.sheet)?
+            .into_iter()
+            .map(|transition| SyncRequest::Transition(player_id, transition))
+            .collect())
     }
 
-    fn backup_landmarks(&self) -> Result<()> {
-        fs::write(
-            format!("{}/landmarks.toml", self.landmarks_dir()),
-            toml::to_string_pretty(&*self.landmarks)?,
-        )?;
-        Ok(())
+    /// Bring in everything in an export, after throwing away what's there if replacing.
+    fn import(&self, export: &Export, mode: ImportMode) {
+        if let ImportMode::Replace = mode {
+            self.players.clear();
+            self.landmarks.clear();
+            self.notes.clear();
+            self.trash.clear();
+            self.rolls.clear();
+        }
+        storage::copy_entries(&export.players, &self.players);
+        storage::copy_entries(&export.landmarks, &self.landmarks);
+        storage::copy_entries(&export.notes, &self.notes);
+        storage::copy_entries(&export.trash, &self.trash);
+        storage::copy_entries(&export.rolls, &self.rolls);
     }
 
-    fn load_landmarks_backup(&mut self) -> Result<()> {
-        self.landmarks = Arc::new(toml::from_str(&fs::read_to_string(format!(
-            "{}/landmarks.toml",
-            self.landmarks_dir()
-        ))?)?);
+    /// Throw away the state in memory and load what's kept in storage again.
+    fn reload(&self) -> Result<()> {
+        let saved = self.storage.load()?;
+        self.players.clear();
+        self.landmarks.clear();
+        self.notes.clear();
+        self.trash.clear();
+        self.rolls.clear();
+        storage::copy_entries(&saved.players, &self.players);
+        storage::copy_entries(&saved.landmarks, &self.landmarks);
+        storage::copy_entries(&saved.notes, &self.notes);
+        storage::copy_entries(&saved.trash, &self.trash);
+        storage::copy_entries(&saved.rolls, &self.rolls);
         Ok(())
     }
 
-    fn add_note(&mut self, title: String, desc: String, cat: NoteCategory) -> NoteId {
-        let id = Uuid::now_v7();
-        self.notes.insert(id, Note { title, desc, cat });
-        id
+    /// A copy of everything there is to save.
+    fn saved(&self) -> storage::Saved {
+        storage::Saved {
+            players: (*self.players).clone(),
+            landmarks: (*self.landmarks).clone(),
+            notes: (*self.notes).clone(),
+            trash: (*self.trash).clone(),
+            rolls: (*self.rolls).clone(),
+        }
     }
 
-    fn edit_note(&mut self, id: NoteId, title: String, desc: String, cat: NoteCategory) {
-        self.notes.insert(id, Note { title, desc, cat });
+    /// Put something that was just deleted in the trash.
+    fn trash_item(&self, id: Uuid, role: &Role, entity: Trashed) -> Outcome {
+        let item = TrashItem::new(id, role.clone(), entity);
+        self.trash.insert(id, item.clone());
+        Outcome::Change {
+            change: SyncRequest::Trash(item.clone()),
+            created: None,
+            undo: Some(SyncRequest::Restore(item)),
+        }
     }
 
-    fn remove_note(&mut self, id: NoteId) {
-        self.notes.remove(&id);
+    /// Bring a change remembered in the history up to date before it's undone or redone by `role`:
+    /// deleting something puts it in the trash as it is now, with any changes made since it was
+    /// remembered, rather than as it was then.
+    fn refresh(&self, change: &SyncRequest, role: &Role) -> SyncRequest {
+        let SyncRequest::Trash(item) = change else {
+            return change.clone();
+        };
+        let entity = match &item.entity {
+            Trashed::Player { .. } => self.players.get(&item.id).map(|player| Trashed::Player {
+                player: player.clone(),
+            }),
+            Trashed::Clock { player_id, .. } => self
+                .players
+                .get(player_id)
+                .and_then(|player| player.clocks.get(&item.id).map(|clock| clock.clone()))
+                .map(|clock| Trashed::Clock {
+                    player_id: *player_id,
+                    clock,
+                }),
+            Trashed::Landmark { .. } => {
+                self.landmarks
+                    .get(&item.id)
+                    .map(|landmark| Trashed::Landmark {
+                        landmark: landmark.clone(),
+                    })
+            }
+            Trashed::Note { .. } => self
+                .notes
+                .get(&item.id)
+                .map(|note| Trashed::Note { note: note.clone() }),
+        };
+        match entity {
+            Some(entity) => SyncRequest::Trash(TrashItem::new(item.id, role.clone(), entity)),
+            // Already gone, so replaying it will fail anyway.
+            None => change.clone(),
+        }
     }
 
-    fn backup_notes(&self) -> Result<()> {
-        fs::write(
-            format!("{}/notes.toml", self.notes_dir()),
-            toml::to_string_pretty(&*self.notes)?,
-        )?;
+    /// Everything that has been in the trash for longer than it is kept.
+    fn expired_trash(&self) -> Vec<Uuid> {
+        self.trash
+            .iter()
+            .filter(|item| item.expired(self.trash_retention_days))
+            .map(|item| *item.key())
+            .collect()
+    }
+
+    /// Permanently delete anything that has been in the trash for longer than it is kept.
+    fn purge_expired(&self) -> Result<()> {
+        for id in self.expired_trash() {
+            self.trash.remove(&id);
+            self.storage.delete_trash(&self.trash, id)?;
+        }
         Ok(())
     }
 
-    fn load_notes_backup(&mut self) -> Result<()> {
-        self.notes = Arc::new(toml::from_str(&fs::read_to_string(format!(
-            "{}/notes.toml",
-            self.notes_dir()
-        ))?)?);
+    /// Apply an instruction from a client to state (or to the campaign's invites). Changes to
+    /// state are only made in memory; [`Bitd::save`] persists them.
+    fn apply(&self, inst: Instruction, role: &Role, access: &Access) -> Result<Outcome> {
+        Ok(match inst {
+            Instruction::FullSync => Outcome::Reply(Reply::Full),
+            Instruction::Resume { since } => Outcome::Reply(Reply::Resume(since)),
+            Instruction::AddClock(player_id, task, slices) => {
+                let (clock_id, clock) = self.add_clock(player_id, task, slices)?;
+                let entity = Trashed::Clock {
+                    player_id,
+                    clock: clock.clone(),
+                };
+                Outcome::Change {
+                    change: SyncRequest::AddClock(player_id, clock_id, clock),
+                    created: Some(clock_id),
+                    undo: Some(SyncRequest::Trash(TrashItem::new(
+                        clock_id,
+                        role.clone(),
+                        entity,
+                    ))),
+                }
+            }
+            Instruction::DeleteClock(player_id, clock_id) => {
+                let clock = self.delete_clock(player_id, clock_id)?;
+                self.trash_item(clock_id, role, Trashed::Clock { player_id, clock })
+            }
+            Instruction::IncrementClock(player_id, clock_id) => {
+                let before = self.clock(player_id, clock_id)?;
+                let clock = self.increment_clock(player_id, clock_id)?;
+                Outcome::Change {
+                    change: SyncRequest::Clock(player_id, clock_id, clock),
+                    created: None,
+                    undo: Some(SyncRequest::Clock(player_id, clock_id, before)),
+                }
+            }
+            Instruction::DecrementClock(player_id, clock_id) => {
+                let before = self.clock(player_id, clock_id)?;
+                let clock = self.decrement_clock(player_id, clock_id)?;
+                Outcome::Change {
+                    change: SyncRequest::Clock(player_id, clock_id, clock),
+                    created: None,
+                    undo: Some(SyncRequest::Clock(player_id, clock_id, before)),
+                }
+            }
+            Instruction::AddPlayer(name) => {
+                let (player_id, player) = self.add_player(name)?;
+                let entity = Trashed::Player {
+                    player: player.clone(),
+                };
+                Outcome::Change {
+                    change: SyncRequest::AddPlayer(player_id, player),
+                    created: Some(player_id),
+                    undo: Some(SyncRequest::Trash(TrashItem::new(
+                        player_id,
+                        role.clone(),
+                        entity,
+                    ))),
+                }
+            }
+            Instruction::RenamePlayer(player_id, name) => {
+                let old_name = self.rename_player(player_id, name.clone())?;
+                Outcome::Change {
+                    change: SyncRequest::RenamePlayer(player_id, name),
+                    created: None,
+                    undo: Some(SyncRequest::RenamePlayer(player_id, old_name)),
+                }
+            }
+            // Stress set by the GM still leads to trauma.
+            Instruction::EditSheet(player_id, SheetField::Stress(stress)) => Outcome::Changes {
+                changes: self.stress_rules(player_id, |sheet| sheet.set_stress(stress))?,
+                created: None,
+            },
+            Instruction::EditSheet(player_id, field) => {
+                let old = self.edit_sheet(player_id, field.clone())?;
+                let change = SyncRequest::Sheet(player_id, field);
+                let retiring =
+                    self.stress_rules(player_id, |sheet| Ok(sheet.retire_if_traumatized()))?;
+                if retiring.is_empty() {
+                    Outcome::Change {
+                        change,
+                        created: None,
+                        undo: Some(SyncRequest::Sheet(player_id, old)),
+                    }
+                } else {
+                    // Like the rest of the rules, retiring can't be undone.
+                    Outcome::Changes {
+                        changes: std::iter::once(change).chain(retiring).collect(),
+                        created: None,
+                    }
+                }
+            }
+            Instruction::PushYourself(player_id) => Outcome::Changes {
+                changes: self.stress_rules(player_id, |sheet| {
+                    sheet.take_stress(PUSH_STRESS, StressReason::Push)
+                })?,
+                created: None,
+            },
+            Instruction::ChooseTrauma(player_id, trauma) => Outcome::Changes {
+                changes: self.stress_rules(player_id, |sheet| sheet.choose_trauma(trauma))?,
+                created: None,
+            },
+            Instruction::DeletePlayer(player_id) => {
+                let player = self.remove_player(player_id)?;
+                self.trash_item(player_id, role, Trashed::Player { player })
+            }
+            Instruction::AddLandmark(name, x, y) => {
+                let (landmark_id, landmark) = self.add_landmark(name, x, y)?;
+                let entity = Trashed::Landmark {
+                    landmark: landmark.clone(),
+                };
+                Outcome::Change {
+                    change: SyncRequest::AddLandmark(landmark_id, landmark),
+                    created: Some(landmark_id),
+                    undo: Some(SyncRequest::Trash(TrashItem::new(
+                        landmark_id,
+                        role.clone(),
+                        entity,
+                    ))),
+                }
+            }
+            Instruction::DeleteLandmark(id) => {
+                let landmark = self.remove_landmark(id)?;
+                self.trash_item(id, role, Trashed::Landmark { landmark })
+            }
+            Instruction::AddNote(title, desc, cat) => {
+                let (note_id, note) = self.add_note(title, desc, cat);
+                let entity = Trashed::Note { note: note.clone() };
+                Outcome::Change {
+                    change: SyncRequest::AddNote(note_id, note),
+                    created: Some(note_id),
+                    undo: Some(SyncRequest::Trash(TrashItem::new(
+                        note_id,
+                        role.clone(),
+                        entity,
+                    ))),
+                }
+            }
+            Instruction::EditNote(id, title, desc, cat) => {
+                let note = Note { title, desc, cat };
+                let previous = self.edit_note(id, note.clone())?;
+                Outcome::Change {
+                    change: SyncRequest::EditNote(id, note),
+                    created: None,
+                    undo: Some(SyncRequest::EditNote(id, previous)),
+                }
+            }
+            Instruction::DeleteNote(id) => {
+                let note = self.remove_note(id)?;
+                self.trash_item(id, role, Trashed::Note { note })
+            }
+            Instruction::ListTrash => {
+                self.purge_expired()?;
+                let mut items: Vec<TrashItem> =
+                    self.trash.iter().map(|item| item.value().clone()).collect();
+                items.sort_by_key(|item| std::cmp::Reverse(item.deleted));
+                Outcome::Reply(Reply::Send(SyncRequest::TrashList(items)))
+            }
+            Instruction::RestoreTrash(id) => {
+                let item = self
+                    .trash
+                    .get(&id)
+                    .map(|item| item.clone())
+                    .ok_or(BitdError::TrashLookup { id })?;
+                if let Trashed::Clock { player_id, .. } = item.entity {
+                    if !self.players.contains_key(&player_id) {
+                        return Err(BitdError::PlayerLookup { player_id });
+                    }
+                }
+                self.replay(&SyncRequest::Restore(item.clone()));
+                Outcome::Change {
+                    change: SyncRequest::Restore(item.clone()),
+                    created: None,
+                    undo: Some(SyncRequest::Trash(item)),
+                }
+            }
+            Instruction::PurgeTrash(id) => {
+                self.trash
+                    .remove(&id)
+                    .ok_or(BitdError::TrashLookup { id })?;
+                Outcome::Change {
+                    change: SyncRequest::Purge(vec![id]),
+                    created: None,
+                    undo: None,
+                }
+            }
+            Instruction::EmptyTrash => {
+                let ids: Vec<Uuid> = self.trash.iter().map(|item| *item.key()).collect();
+                self.trash.clear();
+                Outcome::Change {
+                    change: SyncRequest::Purge(ids),
+                    created: None,
+                    undo: None,
+                }
+            }
+            Instruction::RollDice(pool) => self.roll(role, pool, RollKind::Plain, None)?,
+            Instruction::Roll {
+                pool,
+                kind,
+                player_id,
+            } => self.roll(role, pool, kind, player_id)?,
+            Instruction::RotateSeed => {
+                let revealed = self.dice.rotate()?;
+                Outcome::Change {
+                    change: SyncRequest::Seeded(revealed, self.dice.commitment()),
+                    created: None,
+                    undo: None,
+                }
+            }
+            Instruction::Undo => Outcome::Undo,
+            Instruction::Redo => Outcome::Redo,
+            Instruction::CreateInvite {
+                role,
+                label,
+                expires_in,
+            } => {
+                if let Role::Player { player_id } = role {
+                    if !self.players.contains_key(&player_id) {
+                        return Err(BitdError::PlayerLookup { player_id });
+                    }
+                }
+                let (invite, token) = access.create(role, label, expires_in)?;
+                Outcome::Reply(Reply::Send(SyncRequest::Invite(invite, token)))
+            }
+            Instruction::RevokeInvite(id) => {
+                access.revoke(id)?;
+                Outcome::Reply(Reply::Send(SyncRequest::Invites(access.list())))
+            }
+            Instruction::ListInvites => {
+                Outcome::Reply(Reply::Send(SyncRequest::Invites(access.list())))
+            }
+        })
+    }
+
+    /// Persist a change made by [`Bitd::apply`] or [`Bitd::replay`]. Changes to players that
+    /// have since been deleted have nothing left to save.
+    fn save(&self, change: &SyncRequest) -> Result<()> {
+        match change {
+            SyncRequest::AddClock(player_id, clock_id, _)
+            | SyncRequest::Clock(player_id, clock_id, _) => {
+                if let Some(player) = self.players.get(player_id) {
+                    self.storage.save_clock(*player_id, &player, *clock_id)?;
+                }
+            }
+            SyncRequest::DeleteClock(player_id, clock_id) => {
+                if let Some(player) = self.players.get(player_id) {
+                    self.storage.delete_clock(*player_id, &player, *clock_id)?;
+                }
+            }
+            SyncRequest::AddPlayer(player_id, _)
+            | SyncRequest::RenamePlayer(player_id, _)
+            | SyncRequest::Sheet(player_id, _)
+            | SyncRequest::Transition(player_id, _) => {
+                if let Some(player) = self.players.get(player_id) {
+                    self.storage.save_player(*player_id, &player)?;
+                }
+            }
+            SyncRequest::DeletePlayer(player_id) => self.storage.delete_player(*player_id)?,
+            SyncRequest::AddLandmark(id, _) => self.storage.save_landmark(&self.landmarks, *id)?,
+            SyncRequest::DeleteLandmark(id) => {
+                self.storage.delete_landmark(&self.landmarks, *id)?
+            }
+            SyncRequest::AddNote(id, _) | SyncRequest::EditNote(id, _) => {
+                self.storage.save_note(&self.notes, *id)?
+            }
+            SyncRequest::DeleteNote(id) => self.storage.delete_note(&self.notes, *id)?,
+            SyncRequest::Trash(item) => {
+                match &item.entity {
+                    Trashed::Player { .. } => self.storage.delete_player(item.id)?,
+                    Trashed::Clock { player_id, .. } => {
+                        if let Some(player) = self.players.get(player_id) {
+                            self.storage.delete_clock(*player_id, &player, item.id)?;
+                        }
+                    }
+                    Trashed::Landmark { .. } => {
+                        self.storage.delete_landmark(&self.landmarks, item.id)?
+                    }
+                    Trashed::Note { .. } => self.storage.delete_note(&self.notes, item.id)?,
+                }
+                self.storage.save_trash(&self.trash, item.id)?;
+            }
+            SyncRequest::Restore(item) => {
+                match &item.entity {
+                    Trashed::Player { .. } => {
+                        if let Some(player) = self.players.get(&item.id) {
+                            self.storage.save_player(item.id, &player)?;
+                        }
+                    }
+                    Trashed::Clock { player_id, .. } => {
+                        if let Some(player) = self.players.get(player_id) {
+                            self.storage.save_clock(*player_id, &player, item.id)?;
+                        }
+                    }
+                    Trashed::Landmark { .. } => {
+                        self.storage.save_landmark(&self.landmarks, item.id)?
+                    }
+                    Trashed::Note { .. } => self.storage.save_note(&self.notes, item.id)?,
+                }
+                self.storage.delete_trash(&self.trash, item.id)?;
+            }
+            SyncRequest::Purge(ids) => {
+                for id in ids {
+                    self.storage.delete_trash(&self.trash, *id)?;
+                }
+            }
+            SyncRequest::Import(..) => self.storage.replace(&self.saved())?,
+            SyncRequest::Roll(roll) => self.storage.save_roll(&self.rolls, roll.id)?,
+            // The dice save their own seed as they rotate it.
+            SyncRequest::Seeded(..) => {}
+            SyncRequest::Full
+            | SyncRequest::Error(_)
+            | SyncRequest::Ack(..)
+            | SyncRequest::TrashList(_)
+            | SyncRequest::Invite(..)
+            | SyncRequest::Invites(_) => {}
+        }
         Ok(())
     }
+
+    /// Redo a change read back from the campaign log, or apply one to undo or redo an earlier
+    /// change. Changes carry the resulting entities, so replaying them over state that already
+    /// includes some of them is harmless. Returns false if the change no longer applies, because
+    /// what it changes has since been removed.
+    ///
+    /// Only entities that still exist are updated, and nothing in the trash is added again, so
+    /// that undoing or redoing a change can't bring back something deleted since.
+    fn replay(&self, change: &SyncRequest) -> bool {
+        match change.clone() {
+            SyncRequest::AddClock(_, id, _)
+            | SyncRequest::AddPlayer(id, _)
+            | SyncRequest::AddLandmark(id, _)
+            | SyncRequest::AddNote(id, _)
+                if self.trash.contains_key(&id) =>
+            {
+                false
+            }
+            SyncRequest::AddClock(player_id, clock_id, clock) => self
+                .players
+                .get(&player_id)
+                .map(|player| player.clocks.insert(clock_id, clock))
+                .is_some(),
+            SyncRequest::Clock(player_id, clock_id, clock) => self
+                .players
+                .get(&player_id)
+                .and_then(|player| {
+                    player
+                        .clocks
+                        .get_mut(&clock_id)
+                        .map(|mut existing| *existing = clock)
+                })
+                .is_some(),
+            SyncRequest::DeleteClock(player_id, clock_id) => self
+                .players
+                .get(&player_id)
+                .and_then(|player| player.clocks.remove(&clock_id))
+                .is_some(),
+            SyncRequest::AddPlayer(player_id, player) => {
+                self.players.insert(player_id, player);
+                true
+            }
+            SyncRequest::RenamePlayer(player_id, name) => self
+                .players
+                .get_mut(&player_id)
+                .map(|mut player| player.rename(name))
+                .is_some(),
+            SyncRequest::Sheet(player_id, field) => self
+                .players
+                .get_mut(&player_id)
+                .is_some_and(|mut player| player.sheet.set(field).is_ok()),
+            SyncRequest::Transition(player_id, transition) => self
+                .players
+                .get_mut(&player_id)
+                .map(|mut player| player.sheet.apply(&transition))
+                .is_some(),
+            SyncRequest::DeletePlayer(player_id) => self.players.remove(&player_id).is_some(),
+            SyncRequest::AddLandmark(id, landmark) => {
+                self.landmarks.insert(id, landmark);
+                true
+            }
+            SyncRequest::DeleteLandmark(id) => self.landmarks.remove(&id).is_some(),
+            SyncRequest::AddNote(id, note) => {
+                self.notes.insert(id, note);
+                true
+            }
+            SyncRequest::EditNote(id, note) => self
+                .notes
+                .get_mut(&id)
+                .map(|mut existing| *existing = note)
+                .is_some(),
+            SyncRequest::DeleteNote(id) => self.notes.remove(&id).is_some(),
+            SyncRequest::Trash(item) => {
+                let removed = match &item.entity {
+                    Trashed::Player { .. } => self.players.remove(&item.id).is_some(),
+                    Trashed::Clock { player_id, .. } => self
+                        .players
+                        .get(player_id)
+                        .and_then(|player| player.clocks.remove(&item.id))
+                        .is_some(),
+                    Trashed::Landmark { .. } => self.landmarks.remove(&item.id).is_some(),
+                    Trashed::Note { .. } => self.notes.remove(&item.id).is_some(),
+                };
+                self.trash.insert(item.id, item);
+                removed
+            }
+            // Purged items are gone for good, even if the deletion is undone.
+            SyncRequest::Restore(item) if !self.trash.contains_key(&item.id) => false,
+            SyncRequest::Restore(item) => {
+                let restored = match item.entity.clone() {
+                    Trashed::Player { player } => {
+                        self.players.insert(item.id, player);
+                        true
+                    }
+                    Trashed::Clock { player_id, clock } => self
+                        .players
+                        .get(&player_id)
+                        .map(|player| player.clocks.insert(item.id, clock))
+                        .is_some(),
+                    Trashed::Landmark { landmark } => {
+                        self.landmarks.insert(item.id, landmark);
+                        true
+                    }
+                    Trashed::Note { note } => {
+                        self.notes.insert(item.id, note);
+                        true
+                    }
+                };
+                if restored {
+                    self.trash.remove(&item.id);
+                }
+                restored
+            }
+            SyncRequest::Purge(ids) => {
+                for id in ids {
+                    self.trash.remove(&id);
+                }
+                true
+            }
+            SyncRequest::Import(export, mode) => {
+                self.import(&export, mode);
+                true
+            }
+            SyncRequest::Roll(roll) => {
+                self.record_roll(roll);
+                true
+            }
+            // The seed was rotated when the change was made, and revealing it again changes
+            // nothing.
+            SyncRequest::Seeded(..) => true,
+            SyncRequest::Full
+            | SyncRequest::Error(_)
+            | SyncRequest::Ack(..)
+            | SyncRequest::TrashList(_)
+            | SyncRequest::Invite(..)
+            | SyncRequest::Invites(_) => false,
+        }
+    }
 }
 
 // Our shared state
 struct AppState {
-    bitd: Bitd,
-    // Channel used to send messages to all connected clients.
-    tx: broadcast::Sender<SyncRequest>,
+    campaigns: Campaigns,
+    // Number of replies buffered for each client.
+    reply_capacity: usize,
+}
+
+/// Messages sent to the send task of a single connection rather than broadcast to everyone.
+#[derive(Debug, Clone)]
+enum Reply {
+    Full,
+    Resume(u64),
+    /// The result of a request, and the revision its changes brought state to, if it made any.
+    Ack(u64, AckResult, Option<u64>),
+    Error(BitdError),
+    /// Any other packet meant for this client only.
+    Send(SyncRequest),
+}
+
+/// The result of an instruction, reported back to the client that sent it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum AckResult {
+    /// The instruction was applied. `created` is the id of the entity it added, if any.
+    Ok {
+        created: Option<Uuid>,
+    },
+    Error {
+        error: BitdError,
+    },
+}
+
+/// What applying an instruction produced.
+#[derive(Debug)]
+enum Outcome {
+    /// Something to send back to the client that sent the instruction only.
+    Reply(Reply),
+    /// A change to state to broadcast to every client, along with the id of the entity it
+    /// created, if any, and the change that would undo it, if it can be undone.
+    Change {
+        change: SyncRequest,
+        created: Option<Uuid>,
+        undo: Option<SyncRequest>,
+    },
+    /// Several changes made one after another, each broadcast on its own. None can be undone.
+    Changes {
+        changes: Vec<SyncRequest>,
+        created: Option<Uuid>,
+    },
+    /// Undo or redo the latest change the client may undo or redo.
+    Undo,
+    Redo,
+}
+
+/// A message received from a client: an instruction, optionally tagged with an id that the server
+/// will acknowledge it by.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ClientMessage {
+    Tagged {
+        request_id: u64,
+        instruction: Instruction,
+    },
+    Bare(Instruction),
+}
+
+impl ClientMessage {
+    /// Parse a message into its request id (if any) and instruction. The request id is found
+    /// even if the instruction is invalid, so that the error can be acknowledged.
+    fn parse(text: &str) -> (Option<u64>, Result<Instruction>) {
+        match serde_json::from_str(text) {
+            Ok(ClientMessage::Tagged {
+                request_id,
+                instruction,
+            }) => (Some(request_id), Ok(instruction)),
+            Ok(ClientMessage::Bare(inst)) => (None, Ok(inst)),
+            Err(e) => {
+                let value = serde_json::from_str::<serde_json::Value>(text).ok();
+                let request_id = value
+                    .as_ref()
+                    .and_then(|value| value.get("request_id")?.as_u64());
+                // Untagged enums don't say what went wrong, so try again with the instruction
+                // alone to get a more useful error.
+                let error = match (&value, request_id) {
+                    (Some(value), Some(_)) => {
+                        serde_json::from_value::<Instruction>(value["instruction"].clone()).err()
+                    }
+                    _ => serde_json::from_str::<Instruction>(text).err(),
+                };
+                let error = BitdError::InvalidInstruction {
+                    message: error.unwrap_or(e).to_string(),
+                };
+                (request_id, Err(error))
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Instruction {
     /// Sent to the server by the client to request a change to state.
     FullSync,
+    /// Catch up on the changes made since the given revision.
+    Resume {
+        since: u64,
+    },
     AddClock(PlayerId, String, u8),
     DeleteClock(PlayerId, ClockId),
     IncrementClock(PlayerId, ClockId),
     DecrementClock(PlayerId, ClockId),
     AddPlayer(String),
     RenamePlayer(PlayerId, String),
+    /// Change a field of a player's character sheet.
+    EditSheet(PlayerId, SheetField),
+    /// Take stress to push yourself.
+    PushYourself(PlayerId),
+    /// Choose the trauma suffered when stress ran out.
+    ChooseTrauma(PlayerId, sheet::Trauma),
     DeletePlayer(PlayerId),
     AddLandmark(String, f64, f64),
     DeleteLandmark(LandmarkId),
     AddNote(String, String, NoteCategory),
     EditNote(NoteId, String, String, NoteCategory),
     DeleteNote(NoteId),
+    /// Undo the latest change the client may undo: the GM may undo anyone's changes, players
+    /// only their own.
+    Undo,
+    /// Redo the latest change the client undid.
+    Redo,
+    /// List what's in the trash, most recently deleted first.
+    ListTrash,
+    /// Take something out of the trash and put it back where it was.
+    RestoreTrash(Uuid),
+    /// Permanently delete something in the trash.
+    PurgeTrash(Uuid),
+    /// Permanently delete everything in the trash.
+    EmptyTrash,
+    /// Roll a pool of six-sided dice for everyone to see.
+    RollDice(u8),
+    /// Roll a pool of dice for one of the rolls in the rules, resolving how it turned out.
+    Roll {
+        pool: u8,
+        kind: RollKind,
+        /// Player the roll is for, whose stress a resistance roll takes. Players' rolls are
+        /// always for themselves.
+        #[serde(default)]
+        player_id: Option<PlayerId>,
+    },
+    /// Roll from a new random seed, revealing the old one so that rolls made from it can be
+    /// checked.
+    RotateSeed,
+    /// Create an invite to the campaign, optionally expiring after some number of seconds.
+    CreateInvite {
+        role: Role,
+        label: String,
+        expires_in: Option<u64>,
+    },
+    RevokeInvite(InviteId),
+    ListInvites,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum SyncRequest {
-    /// Messages broadcast to the send task to trigger a state update to any websocket clients
+    /// Messages broadcast to the send task to trigger a state update to any websocket clients.
+    /// Deltas carry a snapshot of the changed entity taken at the moment of mutation, so the send
+    /// task never has to read live state that may have changed (or been deleted) since.
     Full,
-    Error(String),
-    Clock(PlayerId, ClockId),
+    Error(BitdError),
+    Ack(u64, AckResult),
+    /// A clock that was added.
+    AddClock(PlayerId, ClockId, Clock),
+    /// The new state of an existing clock.
+    Clock(PlayerId, ClockId, Clock),
     DeleteClock(PlayerId, ClockId),
-    AddPlayer(PlayerId),
-    RenamePlayer(PlayerId),
+    AddPlayer(PlayerId, PlayerData),
+    RenamePlayer(PlayerId, String),
+    /// A field of a player's sheet and its new value.
+    Sheet(PlayerId, SheetField),
+    /// A step of the stress and trauma rules taken by a player.
+    Transition(PlayerId, Transition),
     DeletePlayer(PlayerId),
-    AddLandmark(LandmarkId),
+    AddLandmark(LandmarkId, Landmark),
     DeleteLandmark(LandmarkId),
-    AddNote(NoteId),
-    EditNote(NoteId),
+    AddNote(NoteId, Note),
+    EditNote(NoteId, Note),
     DeleteNote(NoteId),
+    /// Something was deleted and put in the trash.
+    Trash(TrashItem),
+    /// Something was taken back out of the trash.
+    Restore(TrashItem),
+    /// Things in the trash were permanently deleted.
+    Purge(Vec<Uuid>),
+    /// An exported campaign was imported. Clients are sent a full sync.
+    Import(Box<Export>, ImportMode),
+    Roll(Roll),
+    TrashList(Vec<TrashItem>),
+    /// A seed that was revealed, and the commitment to the new seed rolls are made from now.
+    Seeded(Seed, String),
+    Invite(Invite, String),
+    Invites(Vec<Invite>),
+}
+
+impl SyncRequest {
+    /// Whether this request describes a change to state.
+    fn is_delta(&self) -> bool {
+        !matches!(
+            self,
+            SyncRequest::Full
+                | SyncRequest::Error(_)
+                | SyncRequest::Ack(..)
+                | SyncRequest::TrashList(_)
+                | SyncRequest::Invite(..)
+                | SyncRequest::Invites(_)
+        )
+    }
+
+    /// Whether sending this brings a client up to the revision it's stamped with.
+    fn brings_up_to_date(&self) -> bool {
+        self.is_delta() || matches!(self, SyncRequest::Full)
+    }
+
+    /// Build the packet to send to a client. Only full syncs read from live state.
+    fn packet<'a>(&'a self, bitd: &'a Bitd) -> UpdatePacket<'a> {
+        match self {
+            SyncRequest::Full | SyncRequest::Import(..) => UpdatePacket::Full {
+                players: &bitd.players,
+                landmarks: &bitd.landmarks,
+                notes: &bitd.notes,
+                rolls: &bitd.rolls,
+            },
+            SyncRequest::Error(error) => UpdatePacket::Error {
+                error,
+                text: format!("{error}"),
+            },
+            SyncRequest::Ack(request_id, result) => UpdatePacket::Ack {
+                request_id: *request_id,
+                result,
+            },
+            SyncRequest::AddClock(player_id, clock_id, clock)
+            | SyncRequest::Clock(player_id, clock_id, clock) => UpdatePacket::Clock {
+                player_id: *player_id,
+                clock_id: *clock_id,
+                clock,
+            },
+            SyncRequest::DeleteClock(player_id, clock_id) => UpdatePacket::DeleteClock {
+                player_id: *player_id,
+                clock_id: *clock_id,
+            },
+            SyncRequest::AddPlayer(player_id, player_data) => UpdatePacket::Player {
+                player_id: *player_id,
+                player_data,
+            },
+            SyncRequest::RenamePlayer(player_id, player_name) => UpdatePacket::PlayerName {
+                player_id: *player_id,
+                player_name,
+            },
+            SyncRequest::Sheet(player_id, field) => UpdatePacket::Sheet {
+                player_id: *player_id,
+                field,
+            },
+            SyncRequest::Transition(player_id, transition) => {
+                let player_id = *player_id;
+                match transition {
+                    Transition::Stress {
+                        reason,
+                        change,
+                        stress,
+                    } => UpdatePacket::Stress {
+                        player_id,
+                        reason: *reason,
+                        change: *change,
+                        stress: *stress,
+                    },
+                    Transition::Trauma { choices, pending } => UpdatePacket::Trauma {
+                        player_id,
+                        choices,
+                        pending: *pending,
+                    },
+                    Transition::TraumaChosen { trauma, pending } => UpdatePacket::TraumaChosen {
+                        player_id,
+                        trauma,
+                        pending: *pending,
+                    },
+                    Transition::Retired => UpdatePacket::Retired { player_id },
+                }
+            }
+            SyncRequest::DeletePlayer(player_id) => UpdatePacket::DeletePlayer {
+                player_id: *player_id,
+            },
+            SyncRequest::AddLandmark(id, data) => UpdatePacket::Landmark { id: *id, data },
+            SyncRequest::DeleteLandmark(id) => UpdatePacket::DeleteLandmark { id: *id },
+            SyncRequest::AddNote(id, data) | SyncRequest::EditNote(id, data) => {
+                UpdatePacket::Note { id: *id, data }
+            }
+            SyncRequest::DeleteNote(id) => UpdatePacket::DeleteNote { id: *id },
+            // Clients see things going in and out of the trash as them being deleted and added.
+            SyncRequest::Trash(item) => match &item.entity {
+                Trashed::Player { .. } => UpdatePacket::DeletePlayer { player_id: item.id },
+                Trashed::Clock { player_id, .. } => UpdatePacket::DeleteClock {
+                    player_id: *player_id,
+                    clock_id: item.id,
+                },
+                Trashed::Landmark { .. } => UpdatePacket::DeleteLandmark { id: item.id },
+                Trashed::Note { .. } => UpdatePacket::DeleteNote { id: item.id },
+            },
+            SyncRequest::Restore(item) => match &item.entity {
+                Trashed::Player { player } => UpdatePacket::Player {
+                    player_id: item.id,
+                    player_data: player,
+                },
+                Trashed::Clock { player_id, clock } => UpdatePacket::Clock {
+                    player_id: *player_id,
+                    clock_id: item.id,
+                    clock,
+                },
+                Trashed::Landmark { landmark } => UpdatePacket::Landmark {
+                    id: item.id,
+                    data: landmark,
+                },
+                Trashed::Note { note } => UpdatePacket::Note {
+                    id: item.id,
+                    data: note,
+                },
+            },
+            SyncRequest::Purge(ids) => UpdatePacket::Purged { ids },
+            SyncRequest::Roll(roll) => UpdatePacket::Roll { roll },
+            SyncRequest::TrashList(items) => UpdatePacket::Trash { items },
+            SyncRequest::Seeded(revealed, commitment) => UpdatePacket::Seeded {
+                revealed: *revealed,
+                commitment,
+            },
+            SyncRequest::Invite(invite, token) => UpdatePacket::Invite { invite, token },
+            SyncRequest::Invites(invites) => UpdatePacket::Invites { invites },
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -371,10 +1423,16 @@ enum UpdatePacket<'a> {
         players: &'a DashMap<PlayerId, PlayerData>,
         landmarks: &'a DashMap<LandmarkId, Landmark>,
         notes: &'a DashMap<NoteId, Note>,
+        rolls: &'a DashMap<RollId, Roll>,
     },
     Error {
+        error: &'a BitdError,
         text: String,
     },
+    Ack {
+        request_id: u64,
+        result: &'a AckResult,
+    },
     Clock {
         player_id: PlayerId,
         clock_id: ClockId,
@@ -392,6 +1450,37 @@ enum UpdatePacket<'a> {
         player_id: PlayerId,
         player_name: &'a str,
     },
+    /// A change to one field of a player's sheet, e.g.
+    /// `{"type": "Sheet", "player_id": ..., "field": "stress", "value": 3}`.
+    Sheet {
+        player_id: PlayerId,
+        #[serde(flatten)]
+        field: &'a SheetField,
+    },
+    /// A player took stress, or cleared it, leaving them with `stress`.
+    Stress {
+        player_id: PlayerId,
+        reason: StressReason,
+        change: i8,
+        stress: u8,
+    },
+    /// A player's stress ran out and was cleared, and they must choose one of `choices` as
+    /// their trauma, leaving `pending` traumas to choose.
+    Trauma {
+        player_id: PlayerId,
+        choices: &'a [sheet::Trauma],
+        pending: u8,
+    },
+    /// A player chose a trauma, leaving them with `trauma` and `pending` more to choose.
+    TraumaChosen {
+        player_id: PlayerId,
+        trauma: &'a [sheet::Trauma],
+        pending: u8,
+    },
+    /// A player's scoundrel retired after too much trauma.
+    Retired {
+        player_id: PlayerId,
+    },
     DeletePlayer {
         player_id: PlayerId,
     },
@@ -409,6 +1498,39 @@ enum UpdatePacket<'a> {
     DeleteNote {
         id: NoteId,
     },
+    Roll {
+        roll: &'a Roll,
+    },
+    /// Ids of things permanently deleted from the trash.
+    Purged {
+        ids: &'a [Uuid],
+    },
+    Trash {
+        items: &'a [TrashItem],
+    },
+    /// The seed that rolls were made from until now, and the commitment to the new one.
+    Seeded {
+        revealed: Seed,
+        commitment: &'a str,
+    },
+    /// A newly created invite and its token, which is only ever sent to the GM who created it.
+    Invite {
+        invite: &'a Invite,
+        token: &'a str,
+    },
+    Invites {
+        invites: &'a [Invite],
+    },
+}
+
+/// An update packet stamped with the revision of state it brings the client up to. Replies that
+/// don't carry state, like acknowledgements, have no revision.
+#[derive(Serialize, Debug)]
+struct Envelope<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rev: Option<u64>,
+    #[serde(flatten)]
+    packet: UpdatePacket<'a>,
 }
 
 #[tokio::main]
@@ -416,363 +1538,522 @@ async fn main() {
     // use this to preview json reprs of newly defined types
     // dbg!(serde_json::to_string(&bup));
 
-    let bitd = Bitd::new("./data".into());
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not load configuration. Cause:\n {e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    // Commands print what they did, so anything they log goes to stderr instead.
+    let log_writer = match config.command {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_new(&config.log_level)
+                .unwrap_or_else(|_| "bitd_server=info".into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(log_writer))
+        .init();
+
+    if let Some(command) = config.command.clone() {
+        if let Err(e) = export::run(command, &Campaigns::for_command(config)) {
+            eprintln!("Failed. Cause:\n {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if config.admin_token.is_none() && !config.bind.is_loopback() {
+        tracing::warn!("no admin_token is set, so anyone can create and archive campaigns");
+    }
 
     // Set up application state for use with with_state().
-    let (tx, _rx) = broadcast::channel(100);
-    let shared_state = Arc::new(AppState { bitd, tx });
+    let shared_state = Arc::new(AppState {
+        campaigns: Campaigns::new(config.clone()),
+        reply_capacity: config.reply_capacity,
+    });
 
-    let spa = SpaRouter::new("", "../client");
+    let spa = SpaRouter::new("", &config.static_dir);
     let app = Router::new()
         .merge(spa)
-        .route("/ws", get(websocket_handler))
+        .route("/ws", get(default_websocket_handler))
+        .route("/c/:campaign/ws", get(websocket_handler))
+        .route("/campaigns", get(list_campaigns))
+        .route("/campaigns/:campaign", post(create_campaign))
+        .route("/campaigns/:campaign/archive", post(archive_campaign))
+        .route("/campaigns/:campaign/invite", post(invite_gm))
+        .route("/campaigns/:campaign/export", get(export_campaign))
+        .route("/campaigns/:campaign/import", post(import_campaign))
+        .route(
+            "/campaigns/:campaign/snapshots",
+            get(list_snapshots).post(take_snapshot),
+        )
+        .route(
+            "/campaigns/:campaign/snapshots/:snapshot/restore",
+            post(restore_snapshot),
+        )
         .with_state(shared_state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
+    let addr = config.addr();
+    let served = match &config.tls {
+        Some(tls) => {
+            if let Some(redirect_addr) = config.redirect_addr() {
+                tokio::spawn(async move {
+                    if let Err(e) = tls::redirect(redirect_addr, addr.port()).await {
+                        tracing::error!("HTTP redirect stopped: {e:#}");
+                    }
+                });
+            }
+            tls::serve(app, addr, tls).await
+        }
+        None => serve_http(app, addr).await,
+    };
+    if let Err(e) = served {
+        eprintln!("Server stopped. Cause:\n {e:#}");
+        std::process::exit(1);
+    }
+}
+
+async fn serve_http(app: Router, addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    tracing::info!("listening on http://{addr}");
+    axum::Server::try_bind(&addr)?
         .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .await?;
+    Ok(())
+}
+
+async fn list_campaigns(
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_admin(token.as_deref())?;
+    Ok(Json(state.campaigns.list()?))
+}
+
+async fn create_campaign(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_admin(token.as_deref())?;
+    let (invite, token) = state.campaigns.create(&campaign)?;
+    Ok((StatusCode::CREATED, Json(NewInvite { invite, token })))
+}
+
+/// An invite for a campaign's GM, and its token, which is only ever shown this once.
+#[derive(Serialize, Debug)]
+struct NewInvite {
+    invite: Invite,
+    token: String,
+}
+
+/// Let the server's admin back into a campaign whose GM invites are lost.
+async fn invite_gm(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.require_admin(token.as_deref())?;
+    let (invite, token) = state.campaigns.invite_gm(&campaign, "made by the admin")?;
+    Ok((StatusCode::CREATED, Json(NewInvite { invite, token })))
+}
+
+async fn archive_campaign(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    state.campaigns.archive(&campaign)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Query parameters accepted by routes that need a token.
+#[derive(Deserialize, Debug)]
+struct TokenParams {
+    token: Option<String>,
+}
+
+/// Token an HTTP request was made with, given as a `token` query parameter (as when joining) or
+/// in an `Authorization: Bearer` header.
+struct Token(Option<String>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Token {
+    type Rejection = BitdError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self> {
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let Query(params) = Query::<TokenParams>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| BitdError::validation("token", &e.to_string()))?;
+        Ok(Token(bearer.or(params.token)))
+    }
+}
+
+async fn export_campaign(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    let export = state.campaigns.export(&campaign)?;
+    let disposition = format!("attachment; filename=\"{campaign}.json\"");
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)))
+}
+
+/// Query parameters accepted when importing a campaign.
+#[derive(Deserialize, Debug)]
+struct ImportParams {
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+async fn import_campaign(
+    Path(campaign): Path<String>,
+    Query(params): Query<ImportParams>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+    Json(export): Json<Export>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    state.campaigns.import(&campaign, export, params.mode)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_snapshots(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    Ok(Json(state.campaigns.snapshots(&campaign)?))
+}
+
+async fn take_snapshot(
+    Path(campaign): Path<String>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    Ok((
+        StatusCode::CREATED,
+        Json(state.campaigns.take_snapshot(&campaign)?),
+    ))
+}
+
+async fn restore_snapshot(
+    Path((campaign, snapshot)): Path<(String, String)>,
+    Token(token): Token,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    state.campaigns.authorize_gm(&campaign, token.as_deref())?;
+    state.campaigns.restore_snapshot(&campaign, &snapshot)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Query parameters accepted when opening a websocket.
+#[derive(Deserialize, Debug)]
+struct JoinParams {
+    /// Token identifying the client's role in the campaign.
+    token: Option<String>,
+}
+
+async fn default_websocket_handler(
+    ws: WebSocketUpgrade,
+    query: Query<JoinParams>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse> {
+    websocket_handler(ws, Path(DEFAULT_CAMPAIGN.to_string()), query, State(state)).await
 }
 
 async fn websocket_handler(
     ws: WebSocketUpgrade,
+    Path(name): Path<String>,
+    Query(params): Query<JoinParams>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+) -> Result<impl IntoResponse> {
+    state
+        .campaigns
+        .check_token(&name, params.token.as_deref())?;
+    let (campaign, rx) = state.campaigns.join(&name)?;
+    // The invite may have been revoked since it was checked.
+    let invite = match campaign.access.join(params.token.as_deref()) {
+        Ok(invite) => invite,
+        Err(e) => {
+            drop(rx);
+            state.campaigns.leave(&name);
+            return Err(e);
+        }
+    };
+    Ok(ws.on_upgrade(move |socket| async move {
+        websocket(socket, campaign, rx, invite, state.reply_capacity).await;
+        state.campaigns.leave(&name);
+    }))
 }
 
 // This function deals with a single websocket connection, i.e., a single
 // connected client / user, for which we will spawn two independent tasks (for
 // receiving / sending chat messages).
-async fn websocket(stream: WebSocket, state: Arc<AppState>) {
+async fn websocket(
+    stream: WebSocket,
+    campaign: Arc<Campaign>,
+    mut rx: broadcast::Receiver<Stamped>,
+    invite: Option<InviteId>,
+    reply_capacity: usize,
+) {
     let (mut sender, mut receiver) = stream.split();
-    let mut rx = state.tx.subscribe();
+    // Channel used to send replies to this client only (e.g. a full sync it asked for), as
+    // opposed to deltas, which go to every client through the broadcast channel.
+    let (reply_tx, mut reply_rx) = mpsc::channel(reply_capacity);
 
-    let bitd = state.bitd.clone();
+    let send_state = campaign.clone();
 
     // Spawn the first task that will receive broadcast messages and send text
     // messages over the websocket to our client.
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            match msg {
-                SyncRequest::Full => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Full {
-                                players: &bitd.players,
-                                landmarks: &bitd.landmarks,
-                                notes: &bitd.notes,
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
-                }
-                SyncRequest::Clock(player_id, clock_id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Clock {
-                                player_id,
-                                clock_id,
-                                clock: &bitd
-                                    .players
-                                    .get(&player_id)
-                                    .unwrap()
-                                    .clocks
-                                    .get(&clock_id)
-                                    .unwrap(),
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
-                }
-                SyncRequest::DeleteClock(player_id, clock_id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::DeleteClock {
-                                player_id,
-                                clock_id,
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
-                }
-                SyncRequest::AddPlayer(player_id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Player {
-                                player_id,
-                                player_data: &bitd.players.get(&player_id).unwrap(),
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
-                }
-                SyncRequest::RenamePlayer(player_id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::PlayerName {
-                                player_id,
-                                player_name: &bitd.players.get(&player_id).unwrap().name,
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
+        // The latest revision this client has been sent, used to skip broadcast deltas it has
+        // already caught up on through a full sync.
+        let mut last_rev = 0;
+        // Acks for changes whose deltas haven't been sent yet, which come through the broadcast
+        // channel and can be behind, with the revision each waits for.
+        let mut waiting: VecDeque<(u64, u64, AckResult)> = VecDeque::new();
+        loop {
+            let mut batch = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(stamped) if stamped.sync_req.is_delta() && stamped.rev <= last_rev => {
+                        continue
+                    }
+                    Ok(stamped) => vec![stamped],
+                    // The client missed some updates, so the only way to get it back in sync is
+                    // to send it everything.
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("client lagged behind by {skipped} updates, resyncing");
+                        vec![send_state.full()]
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                Some(reply) = reply_rx.recv() => match reply {
+                    Reply::Full => vec![send_state.full()],
+                    Reply::Resume(since) => send_state.resume(since),
+                    // Acks go out in order, each once the client has the changes it acknowledges.
+                    Reply::Ack(request_id, result, rev) => {
+                        let after = waiting
+                            .back()
+                            .map_or(0, |&(after, ..)| after)
+                            .max(rev.unwrap_or(0));
+                        if after > last_rev {
+                            waiting.push_back((after, request_id, result));
+                            continue;
+                        }
+                        vec![send_state.stamp(SyncRequest::Ack(request_id, result))]
+                    }
+                    Reply::Error(e) => vec![send_state.stamp(SyncRequest::Error(e))],
+                    Reply::Send(sync_req) => vec![send_state.stamp(sync_req)],
+                },
+            };
+            let reached = batch
+                .iter()
+                .filter(|stamped| stamped.sync_req.brings_up_to_date())
+                .fold(last_rev, |reached, stamped| reached.max(stamped.rev));
+            while waiting.front().is_some_and(|&(after, ..)| after <= reached) {
+                let (_, request_id, result) = waiting.pop_front().unwrap();
+                batch.push(send_state.stamp(SyncRequest::Ack(request_id, result)));
+            }
+            for Stamped { rev, sync_req } in batch {
+                // Replies are stamped with whatever revision is current when they're sent, which
+                // may be ahead of a delta still waiting in the broadcast channel. Counting them
+                // would drop that delta.
+                let rev = sync_req.brings_up_to_date().then_some(rev);
+                if let Some(rev) = rev {
+                    last_rev = last_rev.max(rev);
                 }
-                SyncRequest::DeletePlayer(player_id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::DeletePlayer { player_id })
-                                .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
+                let packet = serde_json::to_string(&Envelope {
+                    rev,
+                    packet: sync_req.packet(&send_state.bitd),
+                })
+                .unwrap();
+                if sender.send(Message::Text(packet)).await.is_err() {
+                    return;
+                };
+            }
+        }
+    });
+
+    // This task receives instrutions from the client, performs the appropriate modifications to
+    // app state, and communicates to the send_task to dispatch an appropriate update to the
+    // clients (or just to this client, for replies).
+    let connection = Uuid::now_v7();
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(Message::Text(text))) = receiver.next().await {
+            let (request_id, inst) = ClientMessage::parse(&text);
+            let inst = match inst {
+                Ok(inst) => inst,
+                Err(error) => {
+                    tracing::warn!("could not parse an instruction from a client: {text}");
+                    let reply = match request_id {
+                        Some(request_id) => {
+                            Reply::Ack(request_id, AckResult::Error { error }, None)
+                        }
+                        None => Reply::Error(error),
                     };
-                }
-                SyncRequest::Error(e) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Error {
-                                text: e.to_string(),
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
+                    if reply_tx.send(reply).await.is_err() {
                         break;
                     };
+                    continue;
                 }
-                SyncRequest::AddLandmark(id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Landmark {
-                                id,
-                                data: &bitd.landmarks.get(&id).unwrap(),
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
+            };
+
+            // Look the role up afresh every time, as the invite may have been revoked since.
+            let role = match campaign.access.role(invite) {
+                Ok(role) => role,
+                Err(error) => {
+                    let _ = reply_tx.send(Reply::Error(error)).await;
+                    break;
                 }
-                SyncRequest::DeleteLandmark(id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::DeleteLandmark { id }).unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
+            };
+
+            let cause = Cause {
+                connection: Some(connection),
+                role: role.clone(),
+                instruction: Some(inst.clone()),
+            };
+            let result = match role
+                .authorize(&inst)
+                .and_then(|_| campaign.apply(cause, inst))
+            {
+                Ok(Applied::Reply(reply)) => {
+                    if reply_tx.send(reply).await.is_err() {
                         break;
                     };
+                    Ok((None, None))
                 }
-                SyncRequest::AddNote(id) | SyncRequest::EditNote(id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::Note {
-                                id,
-                                data: &bitd.notes.get(&id).unwrap(),
-                            })
-                            .unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
+                Ok(Applied::Changed(created, rev)) => Ok((created, rev)),
+                Err(e) => Err(e),
+            };
+
+            // Errors only go back to the client that caused them, as an acknowledgement if it
+            // asked for one.
+            let reply = match (request_id, result) {
+                (Some(request_id), Ok((created, rev))) => {
+                    Reply::Ack(request_id, AckResult::Ok { created }, rev)
                 }
-                SyncRequest::DeleteNote(id) => {
-                    if sender
-                        .send(Message::Text(
-                            serde_json::to_string(&UpdatePacket::DeleteNote { id }).unwrap(),
-                        ))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    };
+                (Some(request_id), Err(error)) => {
+                    Reply::Ack(request_id, AckResult::Error { error }, None)
                 }
-            }
+                (None, Err(error)) => Reply::Error(error),
+                (None, Ok(_)) => continue,
+            };
+            if reply_tx.send(reply).await.is_err() {
+                break;
+            };
         }
     });
 
-    // Clone things we want to pass (move) to the receiving task.
-    let tx = state.tx.clone();
-    let mut bitd = state.bitd.clone();
+    // If any one of the tasks run to completion, we abort the other, and wait for it to finish
+    // so that this client's subscription is gone by the time we return.
+    tokio::select! {
+        _ = (&mut send_task) => {
+            recv_task.abort();
+            let _ = recv_task.await;
+        }
+        _ = (&mut recv_task) => {
+            send_task.abort();
+            let _ = send_task.await;
+        }
+    };
+}
 
-    // This task receives instrutions from the client, performs the appropriate modifications to
-    // app state, and communicates to the send_task to dispatch an appropriate update to the
-    // clients.
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(Message::Text(text))) = receiver.next().await {
-            if let Ok(inst) = serde_json::from_str(&text) {
-                match inst {
-                    Instruction::FullSync => {
-                        if tx.send(SyncRequest::Full).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::AddClock(player_id, task, slices) => {
-                        let sync_req = match bitd.add_clock(player_id, task, slices) {
-                            Ok(clock_id) => bitd.backup_player(player_id).map_or_else(
-                                |e| SyncRequest::Error(format!("{e}")),
-                                |_| SyncRequest::Clock(player_id, clock_id),
-                            ),
-                            Err(e) => SyncRequest::Error(format!("{e}")),
-                        };
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::DeleteClock(player_id, clock_id) => {
-                        let sync_req = match bitd.delete_clock(player_id, clock_id) {
-                            Ok(_) => bitd.backup_player(player_id).map_or_else(
-                                |e| SyncRequest::Error(format!("{e}")),
-                                |_| SyncRequest::DeleteClock(player_id, clock_id),
-                            ),
-                            Err(e) => SyncRequest::Error(format!("{e}")),
-                        };
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::IncrementClock(player_id, clock_id) => {
-                        let sync_req = match bitd.increment_clock(player_id, clock_id) {
-                            Ok(_) => bitd.backup_player(player_id).map_or_else(
-                                |e| SyncRequest::Error(format!("{e}")),
-                                |_| SyncRequest::Clock(player_id, clock_id),
-                            ),
-                            Err(e) => SyncRequest::Error(format!("{e}")),
-                        };
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::DecrementClock(player_id, clock_id) => {
-                        let sync_req = match bitd.decrement_clock(player_id, clock_id) {
-                            Ok(_) => bitd.backup_player(player_id).map_or_else(
-                                |e| SyncRequest::Error(format!("{e}")),
-                                |_| SyncRequest::Clock(player_id, clock_id),
-                            ),
-                            Err(e) => SyncRequest::Error(format!("{e}")),
-                        };
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::AddPlayer(name) => {
-                        let player_id = bitd.add_player(name);
-                        let sync_req = bitd.backup_player(player_id).map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::AddPlayer(player_id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::RenamePlayer(player_id, name) => {
-                        bitd.rename_player(player_id, name);
-                        let sync_req = bitd.backup_player(player_id).map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::RenamePlayer(player_id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::DeletePlayer(player_id) => {
-                        bitd.remove_player(player_id);
-                        if tx.send(SyncRequest::DeletePlayer(player_id)).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::AddLandmark(name, x, y) => {
-                        let landmark_id = bitd.add_landmark(name, x, y);
-                        let sync_req = bitd.backup_landmarks().map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::AddLandmark(landmark_id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::DeleteLandmark(id) => {
-                        bitd.remove_landmark(id);
-                        let sync_req = bitd.backup_landmarks().map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::DeleteLandmark(id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::AddNote(title, desc, cat) => {
-                        let note_id = bitd.add_note(title, desc, cat);
-                        let sync_req = bitd.backup_notes().map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::AddNote(note_id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::EditNote(id, title, desc, cat) => {
-                        bitd.edit_note(id, title, desc, cat);
-                        let sync_req = bitd.backup_notes().map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::EditNote(id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                    Instruction::DeleteNote(id) => {
-                        bitd.remove_note(id);
-                        let sync_req = bitd.backup_notes().map_or_else(
-                            |e| SyncRequest::Error(format!("{e}")),
-                            |_| SyncRequest::DeleteNote(id),
-                        );
-                        if tx.send(sync_req).is_err() {
-                            break;
-                        };
-                    }
-                }
-            } else {
-                println!("Received a message from a client but could not parse an instruction:");
-                println!("{}", text);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::TempDir;
+    use crate::storage::MemoryStorage;
+
+    fn load(dir: &std::path::Path) -> Bitd {
+        Bitd::new(
+            Arc::new(MemoryStorage::default()),
+            Dice::load(dir).unwrap(),
+            0,
+            0,
+        )
+        .unwrap()
+    }
+
+    fn apply(bitd: &Bitd, access: &Access, inst: Instruction) -> Option<Uuid> {
+        match bitd.apply(inst, &Role::Gm, access).unwrap() {
+            Outcome::Change {
+                change, created, ..
+            } => {
+                bitd.save(&change).unwrap();
+                created
             }
+            _ => panic!("instruction did not change anything"),
         }
-    });
+    }
 
-    // If any one of the tasks run to completion, we abort the other.
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
-    };
+    #[test]
+    fn undoing_a_change_to_a_deleted_clock_does_not_bring_it_back() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+
+        let bitd = load(&dir.0);
+        let player_id = apply(&bitd, &access, Instruction::AddPlayer("Bob".into())).unwrap();
+        let clock = Instruction::AddClock(player_id, "Escape".into(), 4);
+        let clock_id = apply(&bitd, &access, clock).unwrap();
+        let increment = Instruction::IncrementClock(player_id, clock_id);
+        let Outcome::Change {
+            undo: Some(undo), ..
+        } = bitd.apply(increment, &Role::Gm, &access).unwrap()
+        else {
+            panic!("incrementing a clock can't be undone");
+        };
+        apply(
+            &bitd,
+            &access,
+            Instruction::DeleteClock(player_id, clock_id),
+        );
+
+        assert!(!bitd.replay(&undo));
+        assert!(!bitd
+            .players
+            .get(&player_id)
+            .unwrap()
+            .clocks
+            .contains_key(&clock_id));
+        assert!(bitd.trash.contains_key(&clock_id));
+    }
+
+    #[test]
+    fn undoing_a_purged_deletion_does_not_bring_it_back() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+
+        let bitd = load(&dir.0);
+        let player_id = apply(&bitd, &access, Instruction::AddPlayer("Bob".into())).unwrap();
+        let Outcome::Change {
+            change,
+            undo: Some(undo),
+            ..
+        } = bitd
+            .apply(Instruction::DeletePlayer(player_id), &Role::Gm, &access)
+            .unwrap()
+        else {
+            panic!("deleting a player can't be undone");
+        };
+        bitd.save(&change).unwrap();
+        apply(&bitd, &access, Instruction::PurgeTrash(player_id));
+
+        assert!(!bitd.replay(&undo));
+        assert!(!bitd.players.contains_key(&player_id));
+    }
 }
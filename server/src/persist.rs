@@ -0,0 +1,152 @@
+//! Saving and loading state files so that a crash or full disk can't lose them.
+//!
+//! Files are written to a temporary file that is synced to disk and then renamed over the live
+//! file, so a save either happens completely or not at all. The previous version is kept next to
+//! it with a `.bak` extension. Loading tells a missing file apart from a corrupt one, so that a
+//! campaign with a corrupt file refuses to load rather than starting empty and overwriting it.
+//!
+//! TOML files are saved with the [`schema`] version they were written in, and migrated to the
+//! current version when loaded.
+
+use crate::schema::{self, Document};
+use crate::{BitdError, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Serialize `value` as TOML, along with the current schema version, and save it to `path`.
+pub fn save<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let mut doc = toml::Table::try_from(value)?;
+    doc.insert("version".to_string(), i64::from(schema::VERSION).into());
+    write_atomic(path, toml::to_string_pretty(&doc)?.as_bytes())
+}
+
+/// Load a TOML file saved as a `kind` document, migrating it to the current version, or `None`
+/// if it doesn't exist. A file that exists but can't be read, parsed or migrated is an error.
+pub fn load<T: DeserializeOwned>(path: &Path, kind: Document) -> Result<Option<T>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(corrupt(path, e)),
+    };
+    let mut doc: toml::Table = contents.parse().map_err(|e| corrupt(path, e))?;
+    let version = match doc.remove("version") {
+        None => 0,
+        Some(toml::Value::Integer(version)) => u32::try_from(version)
+            .map_err(|_| corrupt(path, format!("{version} is not a version")))?,
+        Some(other) => return Err(corrupt(path, format!("{other} is not a version"))),
+    };
+    schema::migrate(kind, version, &mut doc).map_err(|message| BitdError::Migration {
+        path: path.display().to_string(),
+        message,
+    })?;
+    doc.try_into().map(Some).map_err(|e| corrupt(path, e))
+}
+
+/// Replace the contents of `path` atomically, keeping the previous contents in a backup.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if path.exists() {
+        fs::copy(path, backup_path(path))?;
+    }
+    fs::rename(&tmp, path)?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+/// Where the previous version of `path` is kept.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+/// Make sure a rename in the directory containing `path` survives a crash.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn corrupt(path: &Path, cause: impl std::fmt::Display) -> BitdError {
+    BitdError::CorruptFile {
+        path: path.display().to_string(),
+        message: format!(
+            "{cause}\nThe previous version, if any, is in {}",
+            backup_path(path).display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{tests::TempDir, SqliteStorage};
+    use crate::{Landmark, LandmarkId};
+    use dashmap::DashMap;
+
+    const LANDMARKS: &str = r#"
+        [0192f0c4-5a8e-7d2c-9b1e-3f4a5b6c7d8e]
+        name = "Crow's Foot"
+        x = 1.5
+        y = -2.0
+    "#;
+
+    #[test]
+    fn unversioned_file_loads() {
+        let dir = TempDir::new();
+        let path = dir.0.join("landmarks.toml");
+        fs::write(&path, LANDMARKS).unwrap();
+
+        let landmarks: DashMap<LandmarkId, Landmark> =
+            load(&path, Document::Landmarks).unwrap().unwrap();
+        assert_eq!(landmarks.len(), 1);
+        assert_eq!(landmarks.iter().next().unwrap().name, "Crow's Foot");
+    }
+
+    #[test]
+    fn newer_file_is_refused() {
+        let dir = TempDir::new();
+        let path = dir.0.join("landmarks.toml");
+        fs::write(
+            &path,
+            format!("version = {}\n{LANDMARKS}", schema::VERSION + 1),
+        )
+        .unwrap();
+
+        let loaded = load::<DashMap<LandmarkId, Landmark>>(&path, Document::Landmarks);
+        assert!(matches!(loaded, Err(BitdError::Migration { .. })));
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let dir = TempDir::new();
+        let conn = rusqlite::Connection::open(dir.0.join("bitd.sqlite3")).unwrap();
+        conn.pragma_update(None, "user_version", schema::VERSION + 1)
+            .unwrap();
+        drop(conn);
+
+        let opened = SqliteStorage::open(&dir.0);
+        assert!(matches!(opened, Err(BitdError::Migration { .. })));
+    }
+}
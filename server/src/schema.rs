@@ -0,0 +1,59 @@
+//! Versions of the formats state is saved in, and the migrations that upgrade older ones.
+//!
+//! Every saved TOML file has a top-level `version`, SQLite databases keep theirs in
+//! `PRAGMA user_version` and every logged event has one. Files saved before versioning have no
+//! version and count as version 0. Older files are upgraded as they are loaded and saved in the
+//! current format the next time they change; anything newer than this server understands, or that
+//! can't be upgraded, stops the campaign from loading instead of being skipped.
+
+/// Version of the formats this server saves in.
+pub const VERSION: u32 = 1;
+
+/// The kinds of file that are saved, each of which is migrated in its own way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Document {
+    /// `players/{id}.toml`
+    Player,
+    /// `landmarks.toml`
+    Landmarks,
+    /// `notes.toml`
+    Notes,
+    /// `trash.toml`
+    Trash,
+    /// `rolls.toml`
+    Rolls,
+    /// `dice.toml`
+    Dice,
+    /// `invites.toml`
+    Invites,
+}
+
+/// Upgrades a document from the version at its index in [`MIGRATIONS`] to the next one.
+type Migration = fn(Document, &mut toml::Table) -> Result<(), String>;
+
+const MIGRATIONS: [Migration; VERSION as usize] = [unversioned];
+
+/// Files from before versioning are laid out just like version 1, which only added new kinds of
+/// document and new optional fields: character sheets on players, for one.
+fn unversioned(_: Document, _: &mut toml::Table) -> Result<(), String> {
+    Ok(())
+}
+
+/// Bring a document saved as `version` up to the current version.
+pub fn migrate(kind: Document, version: u32, doc: &mut toml::Table) -> Result<(), String> {
+    check(version)?;
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(kind, doc)?;
+    }
+    Ok(())
+}
+
+/// Check that something saved as `version` isn't newer than this server understands.
+pub fn check(version: u32) -> Result<(), String> {
+    if version > VERSION {
+        return Err(format!(
+            "It was saved by a newer server, in version {version} of the format, but this server only understands up to version {VERSION}."
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,665 @@
+//! A scoundrel's character sheet: who they are, what they're good at and what the job has cost
+//! them so far. Every player has one, saved in their player file, and it's changed a field at a
+//! time with [`SheetField`]s.
+//!
+//! Stress and trauma also follow the rules on their own. Pushing yourself and resisting
+//! consequences take stress; reaching [`MAX_STRESS`] clears it but leaves a trauma to choose, and
+//! the [`MAX_TRAUMA`]th trauma retires the scoundrel. Each step is a [`Transition`].
+
+use crate::{BitdError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Highest rating an action can have.
+pub const MAX_RATING: u8 = 4;
+/// Stress at which a scoundrel suffers trauma.
+pub const MAX_STRESS: u8 = 9;
+/// Stress taken to push yourself.
+pub const PUSH_STRESS: i8 = 2;
+/// Traumas a scoundrel can have before they must retire.
+pub const MAX_TRAUMA: usize = 4;
+/// Segments in the healing clock.
+pub const HEALING_SEGMENTS: u8 = 4;
+/// XP needed to advance an attribute.
+pub const MAX_ATTRIBUTE_XP: u8 = 6;
+/// XP needed to advance in the playbook.
+pub const MAX_PLAYBOOK_XP: u8 = 8;
+/// Coin a scoundrel can carry.
+pub const MAX_COIN: u8 = 4;
+/// Coin that fits in a stash.
+pub const MAX_STASH: u8 = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Playbook {
+    Cutter,
+    Hound,
+    Leech,
+    Lurk,
+    Slide,
+    Spider,
+    Whisper,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Heritage {
+    Akoros,
+    DaggerIsles,
+    Iruvia,
+    Severos,
+    Skovlan,
+    Tycheros,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Background {
+    Academic,
+    Labor,
+    Law,
+    Trade,
+    Military,
+    Noble,
+    Underworld,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Vice {
+    Faith,
+    Gambling,
+    Luxury,
+    Obligation,
+    Pleasure,
+    Stupor,
+    Weird,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // Insight
+    Hunt,
+    Study,
+    Survey,
+    Tinker,
+    // Prowess
+    Finesse,
+    Prowl,
+    Skirmish,
+    Wreck,
+    // Resolve
+    Attune,
+    Command,
+    Consort,
+    Sway,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trauma {
+    Cold,
+    Haunted,
+    Obsessed,
+    Paranoid,
+    Reckless,
+    Soft,
+    Unstable,
+    Vicious,
+}
+
+impl Trauma {
+    pub const ALL: [Trauma; 8] = [
+        Trauma::Cold,
+        Trauma::Haunted,
+        Trauma::Obsessed,
+        Trauma::Paranoid,
+        Trauma::Reckless,
+        Trauma::Soft,
+        Trauma::Unstable,
+        Trauma::Vicious,
+    ];
+}
+
+/// Harm suffered, by level. Each level has a fixed number of slots.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Harm {
+    /// Level 1, with two slots.
+    pub lesser: Vec<String>,
+    /// Level 2, with two slots.
+    pub moderate: Vec<String>,
+    /// Level 3.
+    pub severe: Option<String>,
+}
+
+/// Which armor has been used up this score.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Armor {
+    pub armor: bool,
+    pub heavy: bool,
+    pub special: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XpTrack {
+    Playbook,
+    Insight,
+    Prowess,
+    Resolve,
+}
+
+impl XpTrack {
+    /// XP that fills the track.
+    fn max(self) -> u8 {
+        match self {
+            XpTrack::Playbook => MAX_PLAYBOOK_XP,
+            _ => MAX_ATTRIBUTE_XP,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Xp {
+    pub playbook: u8,
+    pub insight: u8,
+    pub prowess: u8,
+    pub resolve: u8,
+}
+
+impl Xp {
+    pub fn track(&self, track: XpTrack) -> u8 {
+        match track {
+            XpTrack::Playbook => self.playbook,
+            XpTrack::Insight => self.insight,
+            XpTrack::Prowess => self.prowess,
+            XpTrack::Resolve => self.resolve,
+        }
+    }
+
+    fn track_mut(&mut self, track: XpTrack) -> &mut u8 {
+        match track {
+            XpTrack::Playbook => &mut self.playbook,
+            XpTrack::Insight => &mut self.insight,
+            XpTrack::Prowess => &mut self.prowess,
+            XpTrack::Resolve => &mut self.resolve,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sheet {
+    pub playbook: Option<Playbook>,
+    pub heritage: Option<Heritage>,
+    pub background: Option<Background>,
+    pub vice: Option<Vice>,
+    /// Action ratings. Actions that aren't listed are rated 0.
+    pub actions: BTreeMap<Action, u8>,
+    pub stress: u8,
+    pub trauma: Vec<Trauma>,
+    pub harm: Harm,
+    /// Segments of the healing clock filled in.
+    pub healing: u8,
+    pub armor: Armor,
+    pub xp: Xp,
+    pub coin: u8,
+    pub stash: u8,
+    /// Traumas suffered that haven't been chosen yet.
+    pub trauma_pending: u8,
+    /// Whether the scoundrel has retired, after too much trauma.
+    pub retired: bool,
+}
+
+/// A single field of a sheet and its value, which is how sheets are changed and how changes are
+/// sent to clients, e.g. `{"field": "stress", "value": 3}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "field", content = "value", rename_all = "snake_case")]
+pub enum SheetField {
+    Playbook(Option<Playbook>),
+    Heritage(Option<Heritage>),
+    Background(Option<Background>),
+    Vice(Option<Vice>),
+    Action { action: Action, rating: u8 },
+    Stress(u8),
+    Trauma(Vec<Trauma>),
+    Harm(Harm),
+    Healing(u8),
+    Armor(Armor),
+    Xp { track: XpTrack, xp: u8 },
+    Coin(u8),
+    Stash(u8),
+    TraumaPending(u8),
+    Retired(bool),
+}
+
+/// Why stress was taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StressReason {
+    Push,
+    Resistance,
+    /// The GM set it directly.
+    Gm,
+}
+
+/// A step of the stress and trauma rules, which is broadcast on its own so that the HUD can make
+/// a moment of it. Each carries the values it leaves the sheet with, so applying one twice is
+/// harmless.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transition {
+    /// Stress was taken, or cleared when `change` is negative, leaving `stress`.
+    Stress {
+        reason: StressReason,
+        change: i8,
+        stress: u8,
+    },
+    /// Stress reached the limit and was cleared. A trauma must be chosen from `choices`.
+    Trauma {
+        choices: Vec<Trauma>,
+        pending: u8,
+    },
+    /// A trauma was chosen, leaving the scoundrel with `trauma`.
+    TraumaChosen {
+        trauma: Vec<Trauma>,
+        pending: u8,
+    },
+    Retired,
+}
+
+impl SheetField {
+    /// Whether the field is looked after by the stress and trauma rules, so that only the GM may
+    /// set it directly.
+    pub fn follows_rules(&self) -> bool {
+        matches!(
+            self,
+            SheetField::Stress(_)
+                | SheetField::Trauma(_)
+                | SheetField::TraumaPending(_)
+                | SheetField::Retired(_)
+        )
+    }
+}
+
+impl Sheet {
+    /// Change a field, returning the value it had before.
+    pub fn set(&mut self, field: SheetField) -> Result<SheetField> {
+        field.validate()?;
+        Ok(match field {
+            SheetField::Playbook(playbook) => {
+                SheetField::Playbook(std::mem::replace(&mut self.playbook, playbook))
+            }
+            SheetField::Heritage(heritage) => {
+                SheetField::Heritage(std::mem::replace(&mut self.heritage, heritage))
+            }
+            SheetField::Background(background) => {
+                SheetField::Background(std::mem::replace(&mut self.background, background))
+            }
+            SheetField::Vice(vice) => SheetField::Vice(std::mem::replace(&mut self.vice, vice)),
+            SheetField::Action { action, rating } => {
+                let old = if rating == 0 {
+                    self.actions.remove(&action)
+                } else {
+                    self.actions.insert(action, rating)
+                };
+                SheetField::Action {
+                    action,
+                    rating: old.unwrap_or(0),
+                }
+            }
+            SheetField::Stress(stress) => {
+                SheetField::Stress(std::mem::replace(&mut self.stress, stress))
+            }
+            SheetField::Trauma(trauma) => {
+                SheetField::Trauma(std::mem::replace(&mut self.trauma, trauma))
+            }
+            SheetField::Harm(harm) => SheetField::Harm(std::mem::replace(&mut self.harm, harm)),
+            SheetField::Healing(healing) => {
+                SheetField::Healing(std::mem::replace(&mut self.healing, healing))
+            }
+            SheetField::Armor(armor) => {
+                SheetField::Armor(std::mem::replace(&mut self.armor, armor))
+            }
+            SheetField::Xp { track, xp } => SheetField::Xp {
+                track,
+                xp: std::mem::replace(self.xp.track_mut(track), xp),
+            },
+            SheetField::Coin(coin) => SheetField::Coin(std::mem::replace(&mut self.coin, coin)),
+            SheetField::Stash(stash) => {
+                SheetField::Stash(std::mem::replace(&mut self.stash, stash))
+            }
+            SheetField::TraumaPending(pending) => {
+                SheetField::TraumaPending(std::mem::replace(&mut self.trauma_pending, pending))
+            }
+            SheetField::Retired(retired) => {
+                SheetField::Retired(std::mem::replace(&mut self.retired, retired))
+            }
+        })
+    }
+
+    /// Check that the scoundrel hasn't retired, as retired scoundrels take no more stress.
+    pub fn check_active(&self) -> Result<()> {
+        if self.retired {
+            return Err(BitdError::validation(
+                "stress",
+                "Retired scoundrels don't take stress.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Take stress, or clear it if `change` is negative, suffering trauma if it reaches the limit.
+    pub fn take_stress(&mut self, change: i8, reason: StressReason) -> Result<Vec<Transition>> {
+        self.check_active()?;
+        let stress = (i16::from(self.stress) + i16::from(change)).clamp(0, MAX_STRESS.into()) as u8;
+        let mut transitions = vec![Transition::Stress {
+            reason,
+            change,
+            stress,
+        }];
+        if change > 0 && stress == MAX_STRESS {
+            // Traumas already waiting to be chosen may be enough to retire the scoundrel, and
+            // there's no suffering another after that.
+            if self.trauma.len() + usize::from(self.trauma_pending) >= MAX_TRAUMA {
+                return Err(BitdError::validation(
+                    "stress",
+                    "This scoundrel retires once their trauma is chosen, so can't suffer more.",
+                ));
+            }
+            transitions.push(Transition::Trauma {
+                choices: Trauma::ALL
+                    .into_iter()
+                    .filter(|trauma| !self.trauma.contains(trauma))
+                    .collect(),
+                pending: self.trauma_pending + 1,
+            });
+        }
+        transitions.iter().for_each(|t| self.apply(t));
+        Ok(transitions)
+    }
+
+    /// Set stress directly, suffering trauma as if it had been taken. A retired scoundrel's
+    /// stress is just set, so the GM can still correct it.
+    pub fn set_stress(&mut self, stress: u8) -> Result<Vec<Transition>> {
+        SheetField::Stress(stress).validate()?;
+        let change = stress as i8 - self.stress as i8;
+        if !self.retired {
+            return self.take_stress(change, StressReason::Gm);
+        }
+        let transition = Transition::Stress {
+            reason: StressReason::Gm,
+            change,
+            stress,
+        };
+        self.apply(&transition);
+        Ok(vec![transition])
+    }
+
+    /// Retire if there's too much trauma to go on, e.g. after the GM set it directly.
+    pub fn retire_if_traumatized(&mut self) -> Vec<Transition> {
+        if self.retired || self.trauma.len() < MAX_TRAUMA {
+            return Vec::new();
+        }
+        self.apply(&Transition::Retired);
+        vec![Transition::Retired]
+    }
+
+    /// Choose a trauma that was suffered, retiring if it's one too many.
+    pub fn choose_trauma(&mut self, trauma: Trauma) -> Result<Vec<Transition>> {
+        if self.trauma_pending == 0 {
+            return Err(BitdError::validation(
+                "trauma",
+                "There is no trauma to choose.",
+            ));
+        }
+        if self.trauma.contains(&trauma) {
+            return Err(BitdError::validation(
+                "trauma",
+                "The same trauma can't be taken twice.",
+            ));
+        }
+        let mut chosen = self.trauma.clone();
+        chosen.push(trauma);
+        let retiring = chosen.len() >= MAX_TRAUMA;
+        let mut transitions = vec![Transition::TraumaChosen {
+            trauma: chosen,
+            // Nothing is left to choose once retired.
+            pending: if retiring { 0 } else { self.trauma_pending - 1 },
+        }];
+        if retiring {
+            transitions.push(Transition::Retired);
+        }
+        transitions.iter().for_each(|t| self.apply(t));
+        Ok(transitions)
+    }
+
+    /// Apply a transition made by [`Sheet::take_stress`] or [`Sheet::choose_trauma`].
+    pub fn apply(&mut self, transition: &Transition) {
+        match transition {
+            Transition::Stress { stress, .. } => self.stress = *stress,
+            Transition::Trauma { pending, .. } => {
+                self.stress = 0;
+                self.trauma_pending = *pending;
+            }
+            Transition::TraumaChosen { trauma, pending } => {
+                self.trauma = trauma.clone();
+                self.trauma_pending = *pending;
+            }
+            // Nothing is left to choose once retired.
+            Transition::Retired => {
+                self.retired = true;
+                self.trauma_pending = 0;
+            }
+        }
+    }
+
+    /// Check that every field holds a value it could have been set to.
+    pub fn validate(&self) -> Result<()> {
+        let mut fields = vec![
+            SheetField::Stress(self.stress),
+            SheetField::Trauma(self.trauma.clone()),
+            SheetField::Harm(self.harm.clone()),
+            SheetField::Healing(self.healing),
+            SheetField::Coin(self.coin),
+            SheetField::Stash(self.stash),
+            SheetField::TraumaPending(self.trauma_pending),
+        ];
+        for (&action, &rating) in &self.actions {
+            fields.push(SheetField::Action { action, rating });
+        }
+        for track in [
+            XpTrack::Playbook,
+            XpTrack::Insight,
+            XpTrack::Prowess,
+            XpTrack::Resolve,
+        ] {
+            fields.push(SheetField::Xp {
+                track,
+                xp: self.xp.track(track),
+            });
+        }
+        fields.iter().try_for_each(SheetField::validate)
+    }
+}
+
+impl SheetField {
+    fn validate(&self) -> Result<()> {
+        match self {
+            SheetField::Action { rating, .. } => at_most("rating", *rating, MAX_RATING),
+            SheetField::Stress(stress) => at_most("stress", *stress, MAX_STRESS),
+            SheetField::Trauma(trauma) => {
+                if trauma.len() > MAX_TRAUMA {
+                    return Err(BitdError::validation(
+                        "trauma",
+                        &format!("At most {MAX_TRAUMA} traumas can be taken."),
+                    ));
+                }
+                if trauma
+                    .iter()
+                    .enumerate()
+                    .any(|(i, t)| trauma[..i].contains(t))
+                {
+                    return Err(BitdError::validation(
+                        "trauma",
+                        "The same trauma can't be taken twice.",
+                    ));
+                }
+                Ok(())
+            }
+            SheetField::Harm(harm) => {
+                if harm.lesser.len() > 2 || harm.moderate.len() > 2 {
+                    return Err(BitdError::validation(
+                        "harm",
+                        "Lesser and moderate harm have two slots each.",
+                    ));
+                }
+                harm.lesser
+                    .iter()
+                    .chain(&harm.moderate)
+                    .chain(&harm.severe)
+                    .try_for_each(|harm| crate::validate_name("harm", harm))
+            }
+            SheetField::Healing(healing) => at_most("healing", *healing, HEALING_SEGMENTS),
+            SheetField::Xp { track, xp } => at_most("xp", *xp, track.max()),
+            SheetField::Coin(coin) => at_most("coin", *coin, MAX_COIN),
+            SheetField::Stash(stash) => at_most("stash", *stash, MAX_STASH),
+            SheetField::TraumaPending(pending) => {
+                at_most("trauma_pending", *pending, MAX_TRAUMA as u8)
+            }
+            SheetField::Playbook(_)
+            | SheetField::Heritage(_)
+            | SheetField::Background(_)
+            | SheetField::Vice(_)
+            | SheetField::Armor(_)
+            | SheetField::Retired(_) => Ok(()),
+        }
+    }
+}
+
+fn at_most(field: &str, value: u8, max: u8) -> Result<()> {
+    if value > max {
+        return Err(BitdError::validation(
+            field,
+            &format!("Must be between 0 and {max}."),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setting_a_field_returns_its_old_value() {
+        let mut sheet = Sheet::default();
+        assert_eq!(
+            sheet.set(SheetField::Stress(4)).unwrap(),
+            SheetField::Stress(0)
+        );
+        let hunt = |rating| SheetField::Action {
+            action: Action::Hunt,
+            rating,
+        };
+        assert_eq!(sheet.set(hunt(2)).unwrap(), hunt(0));
+        assert_eq!(sheet.set(hunt(0)).unwrap(), hunt(2));
+        assert!(sheet.actions.is_empty());
+    }
+
+    #[test]
+    fn out_of_range_values_are_refused() {
+        let mut sheet = Sheet::default();
+        assert!(sheet.set(SheetField::Stress(MAX_STRESS + 1)).is_err());
+        assert!(sheet
+            .set(SheetField::Xp {
+                track: XpTrack::Insight,
+                xp: MAX_ATTRIBUTE_XP + 1,
+            })
+            .is_err());
+        assert!(sheet
+            .set(SheetField::Trauma(vec![Trauma::Cold, Trauma::Cold]))
+            .is_err());
+        assert_eq!(sheet, Sheet::default());
+
+        sheet.coin = MAX_COIN + 1;
+        assert!(sheet.validate().is_err());
+    }
+
+    #[test]
+    fn running_out_of_stress_leads_to_trauma_and_retirement() {
+        let mut sheet = Sheet {
+            stress: 8,
+            trauma: vec![Trauma::Cold, Trauma::Haunted],
+            ..Sheet::default()
+        };
+        let transitions = sheet.take_stress(PUSH_STRESS, StressReason::Push).unwrap();
+        assert_eq!(transitions.len(), 2);
+        assert!(matches!(
+            transitions[1],
+            Transition::Trauma { ref choices, pending: 1 } if choices.len() == Trauma::ALL.len() - 2
+        ));
+        assert_eq!((sheet.stress, sheet.trauma_pending), (0, 1));
+        assert!(sheet.choose_trauma(Trauma::Cold).is_err());
+
+        assert_eq!(sheet.choose_trauma(Trauma::Soft).unwrap().len(), 1);
+        assert!(sheet.choose_trauma(Trauma::Vicious).is_err());
+        sheet.stress = MAX_STRESS - 1;
+        sheet.take_stress(1, StressReason::Resistance).unwrap();
+        let transitions = sheet.choose_trauma(Trauma::Vicious).unwrap();
+        assert_eq!(transitions.last(), Some(&Transition::Retired));
+        assert!(sheet.retired);
+        assert!(sheet.take_stress(1, StressReason::Push).is_err());
+        sheet.validate().unwrap();
+    }
+
+    #[test]
+    fn setting_stress_or_trauma_directly_follows_the_rules() {
+        let mut sheet = Sheet::default();
+        let transitions = sheet.set_stress(MAX_STRESS).unwrap();
+        assert!(matches!(transitions[1], Transition::Trauma { .. }));
+        assert_eq!((sheet.stress, sheet.trauma_pending), (0, 1));
+        assert!(sheet.set_stress(MAX_STRESS + 1).is_err());
+
+        assert!(sheet.retire_if_traumatized().is_empty());
+        sheet
+            .set(SheetField::Trauma(Trauma::ALL[..MAX_TRAUMA].to_vec()))
+            .unwrap();
+        assert_eq!(sheet.retire_if_traumatized(), vec![Transition::Retired]);
+        assert!(sheet.retired);
+        assert_eq!(sheet.trauma_pending, 0);
+        assert_eq!(sheet.set_stress(MAX_STRESS).unwrap().len(), 1);
+        assert_eq!((sheet.stress, sheet.trauma_pending), (MAX_STRESS, 0));
+        sheet.set_stress(0).unwrap();
+        assert_eq!(sheet.stress, 0);
+        assert!(SheetField::Retired(false).follows_rules());
+        assert!(!SheetField::Coin(1).follows_rules());
+    }
+
+    #[test]
+    fn no_more_trauma_is_suffered_than_it_takes_to_retire() {
+        let mut sheet = Sheet {
+            trauma: vec![Trauma::Cold, Trauma::Haunted],
+            ..Sheet::default()
+        };
+        sheet.set_stress(MAX_STRESS).unwrap();
+        sheet.set_stress(MAX_STRESS).unwrap();
+        assert_eq!(sheet.trauma_pending, 2);
+        assert!(sheet.set_stress(MAX_STRESS).is_err());
+        assert!(sheet
+            .take_stress(MAX_STRESS as i8, StressReason::Push)
+            .is_err());
+        assert_eq!((sheet.stress, sheet.trauma_pending), (0, 2));
+
+        // Stress short of the limit is still taken.
+        sheet.take_stress(PUSH_STRESS, StressReason::Push).unwrap();
+        sheet.choose_trauma(Trauma::Soft).unwrap();
+        let transitions = sheet.choose_trauma(Trauma::Vicious).unwrap();
+        assert_eq!(transitions.last(), Some(&Transition::Retired));
+        sheet.validate().unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Timestamped copies of a campaign's whole state, kept in `snapshots/` in its directory as
+//! campaign exports named after the unix time in milliseconds they were taken, e.g.
+//! `snapshots/1692345678901.json`. Only the newest few are kept.
+
+use crate::export::Export;
+use crate::{persist, BitdError, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A snapshot, as listed by `GET /campaigns/{name}/snapshots`.
+#[derive(Serialize, Debug)]
+pub struct SnapshotInfo {
+    /// Name to restore it by.
+    pub name: String,
+    /// Unix time in milliseconds it was taken.
+    pub time: u64,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct Snapshots {
+    dir: PathBuf,
+    /// Number of snapshots kept, or 0 to keep them all.
+    retention: usize,
+}
+
+impl Snapshots {
+    pub fn new(save_dir: &Path, retention: usize) -> Self {
+        Snapshots {
+            dir: save_dir.join("snapshots"),
+            retention,
+        }
+    }
+
+    /// Save a snapshot of `export`, unless it's the same as the latest one and `force` isn't set.
+    /// Returns the snapshot taken, if any.
+    pub fn take(&self, export: &Export, force: bool) -> Result<Option<SnapshotInfo>> {
+        if !force && self.unchanged(export)? {
+            return Ok(None);
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        // Snapshots taken in the same millisecond still get names of their own.
+        while self.path(&time.to_string()).exists() {
+            time += 1;
+        }
+        let name = time.to_string();
+        let json = serde_json::to_vec_pretty(export).map_err(|e| BitdError::Persistence {
+            message: e.to_string(),
+        })?;
+        persist::write_atomic(&self.path(&name), &json)?;
+        self.prune()?;
+        Ok(Some(SnapshotInfo {
+            name,
+            time,
+            size: json.len() as u64,
+        }))
+    }
+
+    /// Every snapshot, newest first.
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let Ok(time) = name.parse() else {
+                continue;
+            };
+            snapshots.push(SnapshotInfo {
+                name: name.to_string(),
+                time,
+                size: entry.metadata()?.len(),
+            });
+        }
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.time));
+        Ok(snapshots)
+    }
+
+    /// Read a snapshot back.
+    pub fn load(&self, name: &str) -> Result<Export> {
+        let lookup = || BitdError::SnapshotLookup {
+            snapshot: name.to_string(),
+        };
+        // Names are only ever digits, which also keeps them from escaping the directory.
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_digit()) {
+            return Err(lookup());
+        }
+        let path = self.path(name);
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(lookup()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&json).map_err(|e| BitdError::CorruptFile {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+
+    /// Whether `export` holds the same state as the latest snapshot.
+    fn unchanged(&self, export: &Export) -> Result<bool> {
+        let Some(latest) = self.list()?.into_iter().next() else {
+            return Ok(false);
+        };
+        // A snapshot that can't be read is as good as missing.
+        let Ok(latest) = self.load(&latest.name) else {
+            return Ok(false);
+        };
+        Ok(matches!((state(&latest), state(export)), (Some(a), Some(b)) if a == b))
+    }
+
+    /// Delete all but the newest snapshots.
+    fn prune(&self) -> Result<()> {
+        if self.retention == 0 {
+            return Ok(());
+        }
+        for old in self.list()?.iter().skip(self.retention) {
+            fs::remove_file(self.path(&old.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// The state in an export, ignoring when it was made. Maps are ordered by key once converted, so
+/// two exports of the same state compare equal.
+fn state(export: &Export) -> Option<serde_json::Value> {
+    let mut value = serde_json::to_value(export).ok()?;
+    value.as_object_mut()?.remove("exported");
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::Dice;
+    use crate::storage::tests::{sample, TempDir};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::Bitd;
+    use std::sync::Arc;
+
+    fn bitd(dir: &Path) -> Bitd {
+        let storage = MemoryStorage::default();
+        storage.replace(&sample()).unwrap();
+        Bitd::new(Arc::new(storage), Dice::load(dir).unwrap(), 0, 0).unwrap()
+    }
+
+    #[test]
+    fn snapshots_load_back_the_same() {
+        let dir = TempDir::new();
+        let bitd = bitd(&dir.0);
+        let snapshots = Snapshots::new(&dir.0, 0);
+        let export = Export::new("test", &bitd);
+        let taken = snapshots.take(&export, false).unwrap().unwrap();
+        assert_eq!(state(&snapshots.load(&taken.name).unwrap()), state(&export));
+
+        // Nothing changed since, so there's nothing to take unless forced.
+        let export = Export::new("test", &bitd);
+        assert!(snapshots.take(&export, false).unwrap().is_none());
+        assert!(matches!(
+            snapshots.load("../snapshot"),
+            Err(BitdError::SnapshotLookup { .. })
+        ));
+    }
+
+    #[test]
+    fn only_the_newest_snapshots_are_kept() {
+        let dir = TempDir::new();
+        let bitd = bitd(&dir.0);
+        let snapshots = Snapshots::new(&dir.0, 2);
+        let mut taken = Vec::new();
+        for _ in 0..3 {
+            let export = Export::new("test", &bitd);
+            taken.push(snapshots.take(&export, true).unwrap().unwrap().name);
+        }
+        let kept: Vec<_> = snapshots
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(kept, [taken[2].clone(), taken[1].clone()]);
+    }
+}
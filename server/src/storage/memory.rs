@@ -0,0 +1,99 @@
+//! Storage that only lasts as long as the server, for trying things out and for tests.
+
+use super::{copy_entries, Saved, Storage};
+use crate::dice::{Roll, RollId};
+use crate::trash::TrashItem;
+use crate::{ClockId, Landmark, LandmarkId, Note, NoteId, PlayerData, PlayerId, Result};
+use dashmap::DashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    saved: Saved,
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> Result<Saved> {
+        Ok(self.saved.clone())
+    }
+
+    fn replace(&self, saved: &Saved) -> Result<()> {
+        self.saved.players.clear();
+        copy_entries(&saved.players, &self.saved.players);
+        self.saved.landmarks.clear();
+        copy_entries(&saved.landmarks, &self.saved.landmarks);
+        self.saved.notes.clear();
+        copy_entries(&saved.notes, &self.saved.notes);
+        self.saved.trash.clear();
+        copy_entries(&saved.trash, &self.saved.trash);
+        self.saved.rolls.clear();
+        copy_entries(&saved.rolls, &self.saved.rolls);
+        Ok(())
+    }
+
+    fn save_player(&self, player_id: PlayerId, player: &PlayerData) -> Result<()> {
+        self.saved.players.insert(player_id, player.clone());
+        Ok(())
+    }
+
+    fn delete_player(&self, player_id: PlayerId) -> Result<()> {
+        self.saved.players.remove(&player_id);
+        Ok(())
+    }
+
+    fn save_clock(&self, player_id: PlayerId, player: &PlayerData, _: ClockId) -> Result<()> {
+        self.save_player(player_id, player)
+    }
+
+    fn delete_clock(&self, player_id: PlayerId, player: &PlayerData, _: ClockId) -> Result<()> {
+        self.save_player(player_id, player)
+    }
+
+    fn save_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        id: LandmarkId,
+    ) -> Result<()> {
+        if let Some(landmark) = landmarks.get(&id) {
+            self.saved.landmarks.insert(id, landmark.clone());
+        }
+        Ok(())
+    }
+
+    fn delete_landmark(&self, _: &DashMap<LandmarkId, Landmark>, id: LandmarkId) -> Result<()> {
+        self.saved.landmarks.remove(&id);
+        Ok(())
+    }
+
+    fn save_note(&self, notes: &DashMap<NoteId, Note>, id: NoteId) -> Result<()> {
+        if let Some(note) = notes.get(&id) {
+            self.saved.notes.insert(id, note.clone());
+        }
+        Ok(())
+    }
+
+    fn delete_note(&self, _: &DashMap<NoteId, Note>, id: NoteId) -> Result<()> {
+        self.saved.notes.remove(&id);
+        Ok(())
+    }
+
+    fn save_trash(&self, trash: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()> {
+        if let Some(item) = trash.get(&id) {
+            self.saved.trash.insert(id, item.clone());
+        }
+        Ok(())
+    }
+
+    fn delete_trash(&self, _: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()> {
+        self.saved.trash.remove(&id);
+        Ok(())
+    }
+
+    fn save_roll(&self, rolls: &DashMap<RollId, Roll>, id: RollId) -> Result<()> {
+        if let Some(roll) = rolls.get(&id) {
+            self.saved.rolls.insert(id, roll.clone());
+        }
+        self.saved.rolls.retain(|id, _| rolls.contains_key(id));
+        Ok(())
+    }
+}
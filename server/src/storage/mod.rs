@@ -0,0 +1,274 @@
+//! Where campaign state is kept between runs. Each backend saves individual players, clocks,
+//! landmarks and notes as they change, and loads everything when a campaign is loaded.
+
+mod memory;
+mod sqlite;
+mod toml;
+
+pub use self::memory::MemoryStorage;
+pub use self::sqlite::SqliteStorage;
+pub use self::toml::TomlStorage;
+
+use crate::dice::{Roll, RollId};
+use crate::trash::TrashItem;
+use crate::{ClockId, Landmark, LandmarkId, Note, NoteId, PlayerData, PlayerId, Result};
+use clap::ValueEnum;
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Everything a campaign saves.
+#[derive(Clone, Debug, Default)]
+pub struct Saved {
+    pub players: DashMap<PlayerId, PlayerData>,
+    pub landmarks: DashMap<LandmarkId, Landmark>,
+    pub notes: DashMap<NoteId, Note>,
+    pub trash: DashMap<Uuid, TrashItem>,
+    pub rolls: DashMap<RollId, Roll>,
+}
+
+/// Copy every entry of `from` into `to`, replacing any with the same id.
+pub fn copy_entries<V: Clone>(from: &DashMap<Uuid, V>, to: &DashMap<Uuid, V>) {
+    for entry in from.iter() {
+        to.insert(*entry.key(), entry.value().clone());
+    }
+}
+
+/// A place to save a campaign's state.
+///
+/// Methods are called after the change they save has been made to the in-memory state, and are
+/// given the surrounding state (the player a clock belongs to, every note, ...) so that backends
+/// which can't save a single entity on its own, like the TOML files, can save what contains it.
+pub trait Storage: Debug + Send + Sync {
+    fn load(&self) -> Result<Saved>;
+    /// Save everything in `saved`, replacing whatever was saved before.
+    fn replace(&self, saved: &Saved) -> Result<()>;
+
+    /// Save a player along with all of their clocks.
+    fn save_player(&self, player_id: PlayerId, player: &PlayerData) -> Result<()>;
+    fn delete_player(&self, player_id: PlayerId) -> Result<()>;
+
+    /// Save one of `player`'s clocks.
+    fn save_clock(&self, player_id: PlayerId, player: &PlayerData, clock_id: ClockId)
+        -> Result<()>;
+    /// Forget a clock that has been removed from `player`.
+    fn delete_clock(
+        &self,
+        player_id: PlayerId,
+        player: &PlayerData,
+        clock_id: ClockId,
+    ) -> Result<()>;
+
+    fn save_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        id: LandmarkId,
+    ) -> Result<()>;
+    fn delete_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        id: LandmarkId,
+    ) -> Result<()>;
+
+    fn save_note(&self, notes: &DashMap<NoteId, Note>, id: NoteId) -> Result<()>;
+    fn delete_note(&self, notes: &DashMap<NoteId, Note>, id: NoteId) -> Result<()>;
+
+    /// Save an item that was put in the trash.
+    fn save_trash(&self, trash: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()>;
+    /// Forget an item that was restored or purged from the trash.
+    fn delete_trash(&self, trash: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()>;
+
+    /// Save a new roll. Rolls no longer in `rolls` have fallen out of the history and can be
+    /// forgotten.
+    fn save_roll(&self, rolls: &DashMap<RollId, Roll>, id: RollId) -> Result<()>;
+}
+
+/// The kinds of storage a server can keep its campaigns in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// TOML files: one per player, plus `landmarks.toml`, `notes.toml`, `trash.toml` and
+    /// `rolls.toml`.
+    #[default]
+    Toml,
+    /// A SQLite database, `bitd.sqlite3`, updated a row at a time.
+    Sqlite,
+    /// Nothing is saved; campaigns start empty every time they are loaded.
+    Memory,
+}
+
+/// Open the storage for the campaign saved in `save_dir`.
+pub fn open(backend: Backend, save_dir: &Path) -> Result<Arc<dyn Storage>> {
+    Ok(match backend {
+        Backend::Toml => Arc::new(TomlStorage::open(save_dir)?),
+        Backend::Sqlite => Arc::new(SqliteStorage::open(save_dir)?),
+        Backend::Memory => Arc::new(MemoryStorage::default()),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::access::Role;
+    use crate::trash::Trashed;
+    use crate::{Clock, NoteCategory};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    /// A fresh campaign directory that is removed when dropped.
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("bitd-test-{}", Uuid::now_v7()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn roll() -> Roll {
+        serde_json::from_value(json!({
+            "id": Uuid::now_v7(),
+            "roller": { "role": "gm" },
+            "pool": 2,
+            "faces": [3, 5],
+            "time": 0,
+        }))
+        .unwrap()
+    }
+
+    /// A campaign with one of everything saved.
+    pub fn sample() -> Saved {
+        let saved = Saved::default();
+        let mut player = PlayerData::new("Bob".into());
+        player
+            .clocks
+            .insert(Uuid::now_v7(), Clock::new("Escape".into(), 4));
+        player.sheet.stress = 3;
+        saved.players.insert(Uuid::now_v7(), player);
+        let landmark = Landmark {
+            name: "Crow's Foot".into(),
+            x: 1.5,
+            y: -2.0,
+        };
+        saved.landmarks.insert(Uuid::now_v7(), landmark);
+        let note = Note {
+            title: "Lyssa".into(),
+            desc: "Runs the Crows".into(),
+            cat: NoteCategory::Person,
+        };
+        saved.notes.insert(Uuid::now_v7(), note.clone());
+        let id = Uuid::now_v7();
+        let item = TrashItem::new(id, Role::Gm, Trashed::Note { note });
+        saved.trash.insert(id, item);
+        let roll = roll();
+        saved.rolls.insert(roll.id, roll);
+        saved
+    }
+
+    /// Saved state as JSON, which can be compared.
+    pub fn json(saved: &Saved) -> serde_json::Value {
+        json!({
+            "players": saved.players,
+            "landmarks": saved.landmarks,
+            "notes": saved.notes,
+            "trash": saved.trash,
+            "rolls": saved.rolls,
+        })
+    }
+
+    /// Everything saved an entity at a time, or all at once, loads back the same.
+    fn saves_and_loads(storage: &dyn Storage) {
+        let saved = sample();
+        for player in saved.players.iter() {
+            storage.save_player(*player.key(), &player).unwrap();
+        }
+        for id in saved.landmarks.iter().map(|landmark| *landmark.key()) {
+            storage.save_landmark(&saved.landmarks, id).unwrap();
+        }
+        for id in saved.notes.iter().map(|note| *note.key()) {
+            storage.save_note(&saved.notes, id).unwrap();
+        }
+        for id in saved.trash.iter().map(|item| *item.key()) {
+            storage.save_trash(&saved.trash, id).unwrap();
+        }
+        for id in saved.rolls.iter().map(|roll| *roll.key()) {
+            storage.save_roll(&saved.rolls, id).unwrap();
+        }
+        assert_eq!(json(&storage.load().unwrap()), json(&saved));
+
+        // Replacing it leaves nothing of what was saved before.
+        let replacement = sample();
+        storage.replace(&replacement).unwrap();
+        assert_eq!(json(&storage.load().unwrap()), json(&replacement));
+    }
+
+    /// Deleting a clock forgets only that clock.
+    fn deletes_clocks(storage: &dyn Storage) {
+        let player_id = Uuid::now_v7();
+        let player = PlayerData::new("Bob".into());
+        let (kept, deleted) = (Uuid::now_v7(), Uuid::now_v7());
+        player.clocks.insert(kept, Clock::new("Escape".into(), 4));
+        player.clocks.insert(deleted, Clock::new("Alarm".into(), 6));
+        storage.save_player(player_id, &player).unwrap();
+
+        player.clocks.remove(&deleted);
+        storage.delete_clock(player_id, &player, deleted).unwrap();
+        let saved = storage.load().unwrap();
+        let loaded = saved.players.get(&player_id).unwrap();
+        assert!(loaded.clocks.contains_key(&kept));
+        assert!(!loaded.clocks.contains_key(&deleted));
+    }
+
+    /// Saving a roll forgets the rolls that have fallen out of the history.
+    fn prunes_rolls(storage: &dyn Storage) {
+        let rolls = DashMap::new();
+        let first = roll();
+        let second = roll();
+        for roll in [first.clone(), second.clone()] {
+            rolls.insert(roll.id, roll.clone());
+            storage.save_roll(&rolls, roll.id).unwrap();
+        }
+
+        rolls.remove(&first.id);
+        let third = roll();
+        rolls.insert(third.id, third.clone());
+        storage.save_roll(&rolls, third.id).unwrap();
+        let saved = storage.load().unwrap();
+        assert!(!saved.rolls.contains_key(&first.id));
+        assert!(saved.rolls.contains_key(&second.id));
+        assert!(saved.rolls.contains_key(&third.id));
+    }
+
+    #[test]
+    fn memory_storage_saves_and_loads() {
+        saves_and_loads(&MemoryStorage::default());
+        deletes_clocks(&MemoryStorage::default());
+        prunes_rolls(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_storage_saves_and_loads() {
+        for check in [saves_and_loads, deletes_clocks, prunes_rolls] {
+            let dir = TempDir::new();
+            check(&SqliteStorage::open(&dir.0).unwrap());
+        }
+    }
+
+    #[test]
+    fn toml_storage_saves_and_loads() {
+        for check in [saves_and_loads, deletes_clocks, prunes_rolls] {
+            let dir = TempDir::new();
+            check(&TomlStorage::open(&dir.0).unwrap());
+        }
+    }
+}
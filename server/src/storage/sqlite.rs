@@ -0,0 +1,462 @@
+//! Storage in a SQLite database, `bitd.sqlite3` in the campaign's directory. Each change updates
+//! only the rows it touches, in a transaction. The database's schema version is kept in
+//! `PRAGMA user_version`.
+
+use super::{Saved, Storage, TomlStorage};
+use crate::dice::{Roll, RollId};
+use crate::schema;
+use crate::trash::TrashItem;
+use crate::{
+    BitdError, Clock, ClockId, Landmark, LandmarkId, Note, NoteCategory, NoteId, PlayerData,
+    PlayerId, Result,
+};
+use dashmap::DashMap;
+use rusqlite::{params, Connection, Transaction};
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const DATABASE_FILE: &str = "bitd.sqlite3";
+
+/// Character sheets, rolls and trashed items are only ever read back whole, so each is kept as
+/// JSON.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS players (
+        id BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        sheet TEXT NOT NULL DEFAULT '{}'
+    );
+    CREATE TABLE IF NOT EXISTS clocks (
+        id BLOB PRIMARY KEY,
+        player_id BLOB NOT NULL REFERENCES players(id) ON DELETE CASCADE,
+        task TEXT NOT NULL,
+        slices INTEGER NOT NULL,
+        progress INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS landmarks (
+        id BLOB PRIMARY KEY,
+        name TEXT NOT NULL,
+        x REAL NOT NULL,
+        y REAL NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS notes (
+        id BLOB PRIMARY KEY,
+        title TEXT NOT NULL,
+        desc TEXT NOT NULL,
+        cat TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS trash (
+        id BLOB PRIMARY KEY,
+        item TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS rolls (
+        id BLOB PRIMARY KEY,
+        roll TEXT NOT NULL
+    );
+";
+
+/// SQL that upgrades the database from the version at its index to the next one. New databases
+/// are version 0 and only need the tables created.
+const MIGRATIONS: [&str; schema::VERSION as usize] = [SCHEMA];
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Open the campaign's database, creating it if needed. A new database starts with whatever
+    /// the campaign had saved in TOML files, so that switching backends doesn't lose anything.
+    pub fn open(save_dir: &Path) -> Result<Self> {
+        let path = save_dir.join(DATABASE_FILE);
+        let existed = path.exists();
+        let mut conn = Connection::open(&path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn).map_err(|message| BitdError::Migration {
+            path: path.display().to_string(),
+            message,
+        })?;
+
+        if !existed {
+            let saved = TomlStorage::open(save_dir)?.load()?;
+            if !saved.players.is_empty()
+                || !saved.landmarks.is_empty()
+                || !saved.notes.is_empty()
+                || !saved.trash.is_empty()
+                || !saved.rolls.is_empty()
+            {
+                let tx = conn.transaction()?;
+                write_all(&tx, &saved)?;
+                tx.commit()?;
+                tracing::info!(
+                    "copied the TOML files in {} into {}",
+                    save_dir.display(),
+                    path.display()
+                );
+            }
+        }
+
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Run `f` in a transaction, committing if it succeeds.
+    fn transaction(&self, f: impl FnOnce(&Transaction) -> rusqlite::Result<()>) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        f(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Bring the database's schema up to the current version.
+fn migrate(conn: &mut Connection) -> std::result::Result<(), String> {
+    let version: u32 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    schema::check(version)?;
+    if version == schema::VERSION {
+        return Ok(());
+    }
+    upgrade(conn, version).map_err(|e| e.to_string())
+}
+
+fn upgrade(conn: &mut Connection, from: u32) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    for sql in &MIGRATIONS[from as usize..] {
+        tx.execute_batch(sql)?;
+    }
+    tx.pragma_update(None, "user_version", schema::VERSION)?;
+    tx.commit()
+}
+
+fn write_all(tx: &Transaction, saved: &Saved) -> rusqlite::Result<()> {
+    for player in saved.players.iter() {
+        write_player(tx, *player.key(), &player)?;
+    }
+    for landmark in saved.landmarks.iter() {
+        write_landmark(tx, *landmark.key(), &landmark)?;
+    }
+    for note in saved.notes.iter() {
+        write_note(tx, *note.key(), &note)?;
+    }
+    for item in saved.trash.iter() {
+        write_trash(tx, *item.key(), &item)?;
+    }
+    for roll in saved.rolls.iter() {
+        write_roll(tx, &roll)?;
+    }
+    Ok(())
+}
+
+fn write_player(
+    tx: &Transaction,
+    player_id: PlayerId,
+    player: &PlayerData,
+) -> rusqlite::Result<()> {
+    let sheet = serde_json::to_string(&player.sheet)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    tx.execute(
+        "INSERT INTO players (id, name, sheet) VALUES (?1, ?2, ?3)
+         ON CONFLICT (id) DO UPDATE SET name = excluded.name, sheet = excluded.sheet",
+        params![player_id, player.name, sheet],
+    )?;
+    tx.execute(
+        "DELETE FROM clocks WHERE player_id = ?1",
+        params![player_id],
+    )?;
+    for clock in player.clocks.iter() {
+        write_clock(tx, player_id, *clock.key(), &clock)?;
+    }
+    Ok(())
+}
+
+fn write_clock(
+    tx: &Transaction,
+    player_id: PlayerId,
+    clock_id: ClockId,
+    clock: &Clock,
+) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO clocks (id, player_id, task, slices, progress) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (id) DO UPDATE SET
+             task = excluded.task, slices = excluded.slices, progress = excluded.progress",
+        params![
+            clock_id,
+            player_id,
+            clock.task,
+            clock.slices,
+            clock.progress
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_landmark(tx: &Transaction, id: LandmarkId, landmark: &Landmark) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO landmarks (id, name, x, y) VALUES (?1, ?2, ?3, ?4)",
+        params![id, landmark.name, landmark.x, landmark.y],
+    )?;
+    Ok(())
+}
+
+fn write_note(tx: &Transaction, id: NoteId, note: &Note) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO notes (id, title, desc, cat) VALUES (?1, ?2, ?3, ?4)",
+        params![id, note.title, note.desc, category_name(&note.cat)],
+    )?;
+    Ok(())
+}
+
+/// Trash is only ever read back whole, so each item is kept as JSON.
+fn write_trash(tx: &Transaction, id: Uuid, item: &TrashItem) -> rusqlite::Result<()> {
+    let item = serde_json::to_string(item)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    tx.execute(
+        "INSERT OR REPLACE INTO trash (id, item) VALUES (?1, ?2)",
+        params![id, item],
+    )?;
+    Ok(())
+}
+
+/// Rolls are only ever read back whole too.
+fn write_roll(tx: &Transaction, roll: &Roll) -> rusqlite::Result<()> {
+    let json = serde_json::to_string(roll)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    tx.execute(
+        "INSERT OR REPLACE INTO rolls (id, roll) VALUES (?1, ?2)",
+        params![roll.id, json],
+    )?;
+    Ok(())
+}
+
+fn category_name(cat: &NoteCategory) -> String {
+    serde_json::to_value(cat)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn category(name: String) -> Result<NoteCategory> {
+    serde_json::from_value(serde_json::Value::String(name)).map_err(|e| BitdError::Persistence {
+        message: e.to_string(),
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Saved> {
+        let conn = self.conn.lock().unwrap();
+        let saved = Saved::default();
+
+        let mut players = conn.prepare("SELECT id, name, sheet FROM players")?;
+        let mut rows = players.query([])?;
+        while let Some(row) = rows.next()? {
+            let mut player = PlayerData::new(row.get(1)?);
+            let sheet: String = row.get(2)?;
+            player.sheet = serde_json::from_str(&sheet).map_err(|e| BitdError::Persistence {
+                message: e.to_string(),
+            })?;
+            saved.players.insert(row.get(0)?, player);
+        }
+
+        let mut clocks =
+            conn.prepare("SELECT player_id, id, task, slices, progress FROM clocks")?;
+        let mut rows = clocks.query([])?;
+        while let Some(row) = rows.next()? {
+            let player_id: PlayerId = row.get(0)?;
+            if let Some(player) = saved.players.get(&player_id) {
+                let clock = Clock {
+                    task: row.get(2)?,
+                    slices: row.get(3)?,
+                    progress: row.get(4)?,
+                };
+                player.clocks.insert(row.get(1)?, clock);
+            }
+        }
+
+        let mut landmarks = conn.prepare("SELECT id, name, x, y FROM landmarks")?;
+        let mut rows = landmarks.query([])?;
+        while let Some(row) = rows.next()? {
+            let landmark = Landmark {
+                name: row.get(1)?,
+                x: row.get(2)?,
+                y: row.get(3)?,
+            };
+            saved.landmarks.insert(row.get(0)?, landmark);
+        }
+
+        let mut notes = conn.prepare("SELECT id, title, desc, cat FROM notes")?;
+        let mut rows = notes.query([])?;
+        while let Some(row) = rows.next()? {
+            let note = Note {
+                title: row.get(1)?,
+                desc: row.get(2)?,
+                cat: category(row.get(3)?)?,
+            };
+            saved.notes.insert(row.get(0)?, note);
+        }
+
+        let mut trash = conn.prepare("SELECT id, item FROM trash")?;
+        let mut rows = trash.query([])?;
+        while let Some(row) = rows.next()? {
+            let item: String = row.get(1)?;
+            let item = serde_json::from_str(&item).map_err(|e| BitdError::Persistence {
+                message: e.to_string(),
+            })?;
+            saved.trash.insert(row.get(0)?, item);
+        }
+
+        let mut rolls = conn.prepare("SELECT id, roll FROM rolls")?;
+        let mut rows = rolls.query([])?;
+        while let Some(row) = rows.next()? {
+            let roll: String = row.get(1)?;
+            let roll = serde_json::from_str(&roll).map_err(|e| BitdError::Persistence {
+                message: e.to_string(),
+            })?;
+            saved.rolls.insert(row.get(0)?, roll);
+        }
+
+        Ok(saved)
+    }
+
+    fn replace(&self, saved: &Saved) -> Result<()> {
+        self.transaction(|tx| {
+            // Deleting players deletes their clocks too.
+            tx.execute_batch(
+                "DELETE FROM players; DELETE FROM landmarks; DELETE FROM notes; DELETE FROM trash;
+                 DELETE FROM rolls;",
+            )?;
+            write_all(tx, saved)
+        })
+    }
+
+    fn save_player(&self, player_id: PlayerId, player: &PlayerData) -> Result<()> {
+        self.transaction(|tx| write_player(tx, player_id, player))
+    }
+
+    fn delete_player(&self, player_id: PlayerId) -> Result<()> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM players WHERE id = ?1", params![player_id])?;
+            Ok(())
+        })
+    }
+
+    fn save_clock(
+        &self,
+        player_id: PlayerId,
+        player: &PlayerData,
+        clock_id: ClockId,
+    ) -> Result<()> {
+        let Some(clock) = player.clocks.get(&clock_id) else {
+            return Ok(());
+        };
+        self.transaction(|tx| write_clock(tx, player_id, clock_id, &clock))
+    }
+
+    fn delete_clock(&self, _: PlayerId, _: &PlayerData, clock_id: ClockId) -> Result<()> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM clocks WHERE id = ?1", params![clock_id])?;
+            Ok(())
+        })
+    }
+
+    fn save_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        id: LandmarkId,
+    ) -> Result<()> {
+        let Some(landmark) = landmarks.get(&id) else {
+            return Ok(());
+        };
+        self.transaction(|tx| write_landmark(tx, id, &landmark))
+    }
+
+    fn delete_landmark(&self, _: &DashMap<LandmarkId, Landmark>, id: LandmarkId) -> Result<()> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM landmarks WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+    }
+
+    fn save_note(&self, notes: &DashMap<NoteId, Note>, id: NoteId) -> Result<()> {
+        let Some(note) = notes.get(&id) else {
+            return Ok(());
+        };
+        self.transaction(|tx| write_note(tx, id, &note))
+    }
+
+    fn delete_note(&self, _: &DashMap<NoteId, Note>, id: NoteId) -> Result<()> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM notes WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+    }
+
+    fn save_trash(&self, trash: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()> {
+        let Some(item) = trash.get(&id) else {
+            return Ok(());
+        };
+        self.transaction(|tx| write_trash(tx, id, &item))
+    }
+
+    fn delete_trash(&self, _: &DashMap<Uuid, TrashItem>, id: Uuid) -> Result<()> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM trash WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+    }
+
+    fn save_roll(&self, rolls: &DashMap<RollId, Roll>, id: RollId) -> Result<()> {
+        // Ids sort in the order rolls were made, so anything older than the oldest roll kept has
+        // fallen out of the history.
+        let oldest = rolls.iter().map(|roll| *roll.key()).min().unwrap_or(id);
+        let Some(roll) = rolls.get(&id) else {
+            return Ok(());
+        };
+        self.transaction(|tx| {
+            write_roll(tx, &roll)?;
+            tx.execute("DELETE FROM rolls WHERE id < ?1", params![oldest])?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{json, sample, TempDir};
+
+    #[test]
+    fn new_database_starts_with_the_toml_files() {
+        let dir = TempDir::new();
+        let saved = sample();
+        TomlStorage::open(&dir.0).unwrap().replace(&saved).unwrap();
+
+        let storage = SqliteStorage::open(&dir.0).unwrap();
+        assert_eq!(json(&storage.load().unwrap()), json(&saved));
+        drop(storage);
+
+        // Only a new database is copied into, so changes to the files since are not.
+        TomlStorage::open(&dir.0)
+            .unwrap()
+            .replace(&sample())
+            .unwrap();
+        let storage = SqliteStorage::open(&dir.0).unwrap();
+        assert_eq!(json(&storage.load().unwrap()), json(&saved));
+    }
+
+    #[test]
+    fn rolls_alone_are_copied() {
+        let dir = TempDir::new();
+        let saved = Saved {
+            rolls: sample().rolls,
+            ..Saved::default()
+        };
+        TomlStorage::open(&dir.0).unwrap().replace(&saved).unwrap();
+
+        let storage = SqliteStorage::open(&dir.0).unwrap();
+        assert_eq!(storage.load().unwrap().rolls.len(), 1);
+    }
+}
@@ -0,0 +1,294 @@
+//! The original storage: a TOML file per player in `players/`, plus `landmarks.toml`,
+//! `notes.toml`, `trash.toml` and `rolls.toml`. Files are rewritten whole, so saving a clock
+//! saves its player's entire file.
+
+use super::{Saved, Storage};
+use crate::dice::{Roll, RollId};
+use crate::schema::Document;
+use crate::trash::{TrashItem, Trashed};
+use crate::{persist, ClockId, Landmark, LandmarkId, Note, NoteId, PlayerData, PlayerId, Result};
+use dashmap::DashMap;
+use glob::glob;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct TomlStorage {
+    save_dir: PathBuf,
+    // Saves write to a temporary file next to the real one, so two connections must not save the
+    // same file at once.
+    writing: Mutex<()>,
+}
+
+impl TomlStorage {
+    pub fn open(save_dir: &Path) -> Result<Self> {
+        let storage = TomlStorage {
+            save_dir: save_dir.to_path_buf(),
+            writing: Mutex::new(()),
+        };
+        fs::create_dir_all(storage.players_dir())?;
+        Ok(storage)
+    }
+
+    fn players_dir(&self) -> PathBuf {
+        self.save_dir.join("players")
+    }
+
+    fn player_path(&self, player_id: PlayerId) -> PathBuf {
+        self.players_dir().join(format!("{player_id}.toml"))
+    }
+
+    fn landmarks_path(&self) -> PathBuf {
+        self.save_dir.join("landmarks.toml")
+    }
+
+    fn notes_path(&self) -> PathBuf {
+        self.save_dir.join("notes.toml")
+    }
+
+    fn trash_path(&self) -> PathBuf {
+        self.save_dir.join("trash.toml")
+    }
+
+    fn rolls_path(&self) -> PathBuf {
+        self.save_dir.join("rolls.toml")
+    }
+
+    fn save<T: serde::Serialize + ?Sized>(&self, path: &Path, value: &T) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        persist::save(path, value)
+    }
+
+    fn load_players(&self) -> Result<DashMap<PlayerId, PlayerData>> {
+        let players = DashMap::new();
+        for path in glob(&format!("{}/*.toml", self.players_dir().display()))
+            .expect("Failed to read glob pattern.")
+            .filter_map(Result::ok)
+        {
+            // unwrap is safe bc we know path matches *.toml
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            let Ok(uuid) = Uuid::try_parse(&stem) else {
+                tracing::warn!(
+                    "ignoring {}, which is not named after a player",
+                    path.display()
+                );
+                continue;
+            };
+            if let Some(player) = persist::load(&path, Document::Player)? {
+                players.insert(uuid, player);
+            }
+        }
+        Ok(players)
+    }
+
+    /// Drop players that are in the trash, whose files were left behind when they were deleted.
+    fn drop_deleted_players(
+        &self,
+        players: &DashMap<PlayerId, PlayerData>,
+        trash: &DashMap<Uuid, TrashItem>,
+    ) {
+        for item in trash.iter() {
+            if let Trashed::Player { .. } = item.entity {
+                if players.remove(&item.id).is_some() {
+                    tracing::warn!(
+                        "not loading {}, which belongs to a deleted player and can be removed",
+                        self.player_path(item.id).display()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Report files in `players/` that shouldn't be there: anything that isn't a player's file or
+    /// its backup.
+    fn report_orphans(&self) -> Result<()> {
+        for entry in fs::read_dir(self.players_dir())? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(".tmp") {
+                tracing::warn!(
+                    "ignoring {}, which was left by a save that was interrupted",
+                    path.display()
+                );
+            } else if !name.ends_with(".toml") && !name.ends_with(".toml.bak") {
+                tracing::warn!("ignoring {}, which is not a player's file", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for TomlStorage {
+    fn load(&self) -> Result<Saved> {
+        let landmarks =
+            persist::load(&self.landmarks_path(), Document::Landmarks)?.unwrap_or_else(|| {
+                tracing::info!(
+                    "did not find a landmarks backup, one will be created at {}",
+                    self.landmarks_path().display()
+                );
+                DashMap::new()
+            });
+        let notes = persist::load(&self.notes_path(), Document::Notes)?.unwrap_or_else(|| {
+            tracing::info!(
+                "did not find a notes backup, one will be created at {}",
+                self.notes_path().display()
+            );
+            DashMap::new()
+        });
+        // An empty trash is not worth mentioning.
+        let trash = persist::load(&self.trash_path(), Document::Trash)?.unwrap_or_default();
+        let rolls = persist::load(&self.rolls_path(), Document::Rolls)?.unwrap_or_default();
+        let players = self.load_players()?;
+        self.drop_deleted_players(&players, &trash);
+        self.report_orphans()?;
+        Ok(Saved {
+            players,
+            landmarks,
+            notes,
+            trash,
+            rolls,
+        })
+    }
+
+    fn replace(&self, saved: &Saved) -> Result<()> {
+        for player in saved.players.iter() {
+            self.save_player(*player.key(), &player)?;
+        }
+        for player_id in self.load_players()?.iter().map(|player| *player.key()) {
+            if !saved.players.contains_key(&player_id) {
+                self.delete_player(player_id)?;
+            }
+        }
+        self.save(&self.landmarks_path(), &saved.landmarks)?;
+        self.save(&self.notes_path(), &saved.notes)?;
+        self.save(&self.trash_path(), &saved.trash)?;
+        self.save(&self.rolls_path(), &saved.rolls)
+    }
+
+    fn save_player(&self, player_id: PlayerId, player: &PlayerData) -> Result<()> {
+        self.save(&self.player_path(player_id), player)
+    }
+
+    fn delete_player(&self, player_id: PlayerId) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        match fs::remove_file(self.player_path(player_id)) {
+            // Already gone, e.g. when replaying a deletion that was saved before.
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn save_clock(&self, player_id: PlayerId, player: &PlayerData, _: ClockId) -> Result<()> {
+        self.save_player(player_id, player)
+    }
+
+    fn delete_clock(&self, player_id: PlayerId, player: &PlayerData, _: ClockId) -> Result<()> {
+        self.save_player(player_id, player)
+    }
+
+    fn save_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        _: LandmarkId,
+    ) -> Result<()> {
+        self.save(&self.landmarks_path(), landmarks)
+    }
+
+    fn delete_landmark(
+        &self,
+        landmarks: &DashMap<LandmarkId, Landmark>,
+        _: LandmarkId,
+    ) -> Result<()> {
+        self.save(&self.landmarks_path(), landmarks)
+    }
+
+    fn save_note(&self, notes: &DashMap<NoteId, Note>, _: NoteId) -> Result<()> {
+        self.save(&self.notes_path(), notes)
+    }
+
+    fn delete_note(&self, notes: &DashMap<NoteId, Note>, _: NoteId) -> Result<()> {
+        self.save(&self.notes_path(), notes)
+    }
+
+    fn save_trash(&self, trash: &DashMap<Uuid, TrashItem>, _: Uuid) -> Result<()> {
+        self.save(&self.trash_path(), trash)
+    }
+
+    fn delete_trash(&self, trash: &DashMap<Uuid, TrashItem>, _: Uuid) -> Result<()> {
+        self.save(&self.trash_path(), trash)
+    }
+
+    fn save_roll(&self, rolls: &DashMap<RollId, Roll>, _: RollId) -> Result<()> {
+        self.save(&self.rolls_path(), rolls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{Access, Role};
+    use crate::dice::Dice;
+    use crate::storage::tests::TempDir;
+    use crate::{Bitd, Instruction, Outcome};
+    use std::sync::Arc;
+
+    fn load(dir: &Path) -> Bitd {
+        Bitd::new(
+            Arc::new(TomlStorage::open(dir).unwrap()),
+            Dice::load(dir).unwrap(),
+            0,
+            0,
+        )
+        .unwrap()
+    }
+
+    fn apply(bitd: &Bitd, access: &Access, inst: Instruction) -> Option<Uuid> {
+        match bitd.apply(inst, &Role::Gm, access).unwrap() {
+            Outcome::Change {
+                change, created, ..
+            } => {
+                bitd.save(&change).unwrap();
+                created
+            }
+            _ => panic!("instruction did not change anything"),
+        }
+    }
+
+    #[test]
+    fn deleted_player_stays_deleted_after_restart() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+
+        let bitd = load(&dir.0);
+        let player_id = apply(&bitd, &access, Instruction::AddPlayer("Bob".into())).unwrap();
+        let path = dir.0.join("players").join(format!("{player_id}.toml"));
+        assert!(path.exists());
+        apply(&bitd, &access, Instruction::DeletePlayer(player_id));
+        assert!(!path.exists());
+
+        let bitd = load(&dir.0);
+        assert!(!bitd.players.contains_key(&player_id));
+        assert!(bitd.trash.contains_key(&player_id));
+    }
+
+    #[test]
+    fn orphaned_player_file_is_not_loaded() {
+        let dir = TempDir::new();
+        let access = Access::load(&dir.0).unwrap();
+
+        let bitd = load(&dir.0);
+        let player_id = apply(&bitd, &access, Instruction::AddPlayer("Bob".into())).unwrap();
+        let path = dir.0.join("players").join(format!("{player_id}.toml"));
+        let file = fs::read(&path).unwrap();
+        apply(&bitd, &access, Instruction::DeletePlayer(player_id));
+        // As left behind when deleting the file failed.
+        fs::write(&path, file).unwrap();
+
+        let bitd = load(&dir.0);
+        assert!(!bitd.players.contains_key(&player_id));
+        assert!(bitd.trash.contains_key(&player_id));
+    }
+}
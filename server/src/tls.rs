@@ -0,0 +1,102 @@
+//! Serving over HTTPS, and redirecting plain HTTP requests there.
+
+use crate::config::Tls;
+use anyhow::{Context, Result};
+use axum::{
+    extract::Host,
+    http::{
+        uri::{Authority, PathAndQuery},
+        StatusCode, Uri,
+    },
+    response::Redirect,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::net::SocketAddr;
+
+/// Serve `app` over HTTPS until the server fails.
+pub async fn serve(app: Router, addr: SocketAddr, tls: &Tls) -> Result<()> {
+    let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .with_context(|| {
+            format!(
+                "Could not load TLS certificate {} and key {}",
+                tls.cert.display(),
+                tls.key.display()
+            )
+        })?;
+    tracing::info!("listening on https://{addr}");
+    axum_server::bind_rustls(addr, rustls)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Answer plain HTTP requests on `addr` by redirecting them to the same path over HTTPS on
+/// `https_port`.
+pub async fn redirect(addr: SocketAddr, https_port: u16) -> Result<()> {
+    let redirect = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        https_url(&host, https_port, &uri)
+            .map(|url| Redirect::permanent(&url))
+            .ok_or(StatusCode::BAD_REQUEST)
+    });
+    tracing::info!("redirecting http://{addr} to HTTPS");
+    axum::Server::try_bind(&addr)?
+        .serve(redirect.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// The HTTPS URL for a request to `host` (which may include the HTTP port) and `uri`, or `None`
+/// if `host` isn't one.
+fn https_url(host: &str, https_port: u16, uri: &Uri) -> Option<String> {
+    // Only the hostname is kept, so a client can't slip anything else into the redirect. IPv6
+    // addresses keep their brackets, like `[::1]`.
+    let authority: Authority = host.parse().ok()?;
+    let hostname = authority.host();
+    if hostname.is_empty() {
+        return None;
+    }
+    let path = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+    Some(match https_port {
+        443 => format!("https://{hostname}{path}"),
+        port => format!("https://{hostname}:{port}{path}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(host: &str, https_port: u16, uri: &str) -> Option<String> {
+        https_url(host, https_port, &uri.parse().unwrap())
+    }
+
+    #[test]
+    fn redirects_keep_the_host_and_path() {
+        assert_eq!(
+            url("example.com:80", 443, "/ws?token=abc").as_deref(),
+            Some("https://example.com/ws?token=abc")
+        );
+        assert_eq!(
+            url("example.com", 8443, "/").as_deref(),
+            Some("https://example.com:8443/")
+        );
+        assert_eq!(
+            url("[::1]:3000", 3443, "/").as_deref(),
+            Some("https://[::1]:3443/")
+        );
+    }
+
+    #[test]
+    fn redirects_only_go_to_the_hostname() {
+        assert_eq!(
+            url("user@example.com:80", 443, "/").as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(url("", 443, "/"), None);
+        assert_eq!(url(":80", 443, "/"), None);
+        assert_eq!(url("evil.com/path#", 443, "/"), None);
+        assert_eq!(url("[::1", 443, "/"), None);
+    }
+}
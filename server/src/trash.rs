@@ -0,0 +1,59 @@
+//! Deleted players, clocks, landmarks and notes, kept for a while so that they can be restored.
+
+use crate::access::{now, Role};
+use crate::{Clock, Landmark, Note, PlayerData, PlayerId};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Seconds in a day, as trash retention is configured in days.
+const DAY: u64 = 24 * 60 * 60;
+
+/// Something that was deleted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Trashed {
+    /// A player, along with all of their clocks.
+    Player {
+        player: PlayerData,
+    },
+    Clock {
+        player_id: PlayerId,
+        clock: Clock,
+    },
+    Landmark {
+        landmark: Landmark,
+    },
+    Note {
+        note: Note,
+    },
+}
+
+/// An entry in a campaign's trash.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrashItem {
+    /// Id of the deleted entity, which it gets back when restored.
+    pub id: Uuid,
+    /// Unix time it was deleted.
+    pub deleted: u64,
+    /// Role of whoever deleted it.
+    pub by: Role,
+    #[serde(flatten)]
+    pub entity: Trashed,
+}
+
+impl TrashItem {
+    pub fn new(id: Uuid, by: Role, entity: Trashed) -> Self {
+        TrashItem {
+            id,
+            deleted: now(),
+            by,
+            entity,
+        }
+    }
+
+    /// Whether the item has been in the trash longer than `retention_days`. Items are kept
+    /// forever if that's 0.
+    pub fn expired(&self, retention_days: u64) -> bool {
+        retention_days > 0 && now() >= self.deleted.saturating_add(retention_days * DAY)
+    }
+}